@@ -0,0 +1,405 @@
+//! Pipes for use inside a tokio runtime, enabled with the `tokio` feature.
+//!
+//! The blocking pipe calls run on tokio's blocking thread pool, so they never
+//! stall the executor. Streams are opened for overlapped I/O so a read that is
+//! waiting for data doesn't hold up writes.
+
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::runtime::Handle;
+use tokio::task::{self, JoinError, JoinHandle};
+use winapi::{ERROR_BROKEN_PIPE, ERROR_PIPE_NOT_CONNECTED};
+
+use {PipeListener, PipeListenerBuilder, PipeStream, PipeStreamBuilder, ShutdownHandle};
+
+// the most a single read asks the pipe for, however large the caller's buffer.
+const MAX_READ: usize = 65536;
+
+/// An overlapped `PipeStream` implementing tokio's `AsyncRead` and
+/// `AsyncWrite`.
+///
+/// Writes complete as soon as the data has been handed to the blocking pool,
+/// like `tokio::fs::File`. An error from a write is returned by the next
+/// write or flush.
+///
+/// A read that is waiting for data occupies a blocking pool thread until
+/// data arrives, the peer goes away or the stream is dropped, so every
+/// stream with a pending read counts towards the runtime's
+/// `max_blocking_threads`. A server with more idle connections than that
+/// stalls every other blocking task, such as `tokio::fs` calls and accepts,
+/// until a read finishes.
+#[derive(Debug)]
+pub struct AsyncPipeStream {
+    stream: Arc<PipeStream>,
+    read: Option<JoinHandle<io::Result<Vec<u8>>>>,
+    // lets dropping the stream cancel the read without touching writes.
+    read_state: Arc<Mutex<ReadState>>,
+    // data from a finished read that didn't fit in the caller's buffer.
+    unread: Vec<u8>,
+    write: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl AsyncPipeStream {
+    /// Connects to the pipe at `path`, waiting up to `timeout` for the server
+    /// to create it or for a busy instance to become available, like
+    /// `PipeStream::connect_timeout`.
+    ///
+    /// The returned future must be polled from within a tokio runtime.
+    pub fn connect<P: AsRef<Path>>(path: P, timeout: Duration) -> Connect {
+        Connect {
+            path: path.as_ref().to_path_buf(),
+            timeout,
+            task: None,
+        }
+    }
+
+    /// Wraps a connected stream, which must have been opened for overlapped
+    /// I/O.
+    pub fn new(stream: PipeStream) -> io::Result<AsyncPipeStream> {
+        if !stream.is_overlapped() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "AsyncPipeStream requires an overlapped stream"));
+        }
+
+        Ok(AsyncPipeStream {
+            stream: Arc::new(stream),
+            read: None,
+            read_state: Arc::new(Mutex::new(ReadState::Idle)),
+            unread: Vec::new(),
+            write: None,
+        })
+    }
+
+    /// The underlying stream.
+    pub fn get_ref(&self) -> &PipeStream {
+        &self.stream
+    }
+
+    fn poll_write_done(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let result = match self.write {
+            Some(ref mut write) => {
+                match Pin::new(write).poll(cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            None => return Poll::Ready(Ok(())),
+        };
+
+        self.write = None;
+        Poll::Ready(join_result(result))
+    }
+}
+
+impl AsyncRead for AsyncPipeStream {
+    fn poll_read(self: Pin<&mut Self>,
+                 cx: &mut Context,
+                 buf: &mut ReadBuf)
+                 -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        if this.unread.is_empty() {
+            if this.read.is_none() {
+                let stream = this.stream.clone();
+                let state = this.read_state.clone();
+                let len = std::cmp::min(buf.remaining(), MAX_READ);
+                this.read =
+                    Some(task::spawn_blocking(move || read_cancellable(&stream, &state, len)));
+            }
+
+            let result = match this.read {
+                Some(ref mut read) => {
+                    match Pin::new(read).poll(cx) {
+                        Poll::Ready(result) => result,
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                None => unreachable!(),
+            };
+            this.read = None;
+            // an empty read is the end of the stream.
+            this.unread = join_result(result)?;
+        }
+
+        let n = std::cmp::min(buf.remaining(), this.unread.len());
+        buf.put_slice(&this.unread[..n]);
+        this.unread.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for AsyncPipeStream {
+    fn poll_write(self: Pin<&mut Self>,
+                  cx: &mut Context,
+                  buf: &[u8])
+                  -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.poll_write_done(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        let stream = this.stream.clone();
+        let data = buf.to_vec();
+        this.write = Some(task::spawn_blocking(move || (&*stream).write_all(&data)));
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Waits for any write in progress to complete. This doesn't wait for the
+    /// other end to read the data, unlike `PipeStream::flush`.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_write_done(cx)
+    }
+
+    /// Only waits for any write in progress to complete, like `poll_flush`.
+    /// Pipes can't be half-closed, so the other end doesn't see the end of
+    /// the stream until this one is dropped.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_write_done(cx)
+    }
+}
+
+impl Drop for AsyncPipeStream {
+    fn drop(&mut self) {
+        // a read waiting for data would otherwise hold a blocking thread, and
+        // the pipe, until the other end writes or goes away. A write still
+        // running is left to finish, since it was already reported done.
+        let mut state = self.read_state.lock().unwrap();
+        if let ReadState::Pending(overlapped) = *state {
+            self.stream.cancel_overlapped(overlapped);
+        }
+        *state = ReadState::Cancelled;
+    }
+}
+
+// Where the stream's blocking read is up to.
+#[derive(Debug)]
+enum ReadState {
+    Idle,
+    // running, with the address of its OVERLAPPED.
+    Pending(usize),
+    // the stream was dropped, so no more reads should start.
+    Cancelled,
+}
+
+// Reads up to `len` bytes as an overlapped read that dropping the stream can
+// cancel on its own, unlike `CancelIoEx` on the whole handle.
+fn read_cancellable(stream: &PipeStream,
+                    state: &Mutex<ReadState>,
+                    len: usize)
+                    -> io::Result<Vec<u8>> {
+    let mut read = {
+        let mut state = state.lock().unwrap();
+        if let ReadState::Cancelled = *state {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the stream was dropped"));
+        }
+        let read = stream.read_overlapped(vec![0; len])?;
+        *state = ReadState::Pending(read.overlapped_addr());
+        read
+    };
+    let result = read.wait();
+    {
+        // the OVERLAPPED mustn't be cancelled once `read` is freed.
+        let mut state = state.lock().unwrap();
+        if let ReadState::Pending(_) = *state {
+            *state = ReadState::Idle;
+        }
+    }
+
+    let n = match result {
+        Ok(n) => n,
+        // the other end disconnected or closed its handle, the end of the
+        // stream.
+        Err(ref err) if err.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) ||
+                        err.raw_os_error() == Some(ERROR_PIPE_NOT_CONNECTED as i32) => 0,
+        Err(err) => return Err(err),
+    };
+    stream.shared.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+    let mut data = read.into_buffer();
+    data.truncate(n);
+    Ok(data)
+}
+
+/// The future returned by `AsyncPipeStream::connect`.
+#[derive(Debug)]
+pub struct Connect {
+    path: PathBuf,
+    timeout: Duration,
+    task: Option<JoinHandle<io::Result<PipeStream>>>,
+}
+
+impl Future for Connect {
+    type Output = io::Result<AsyncPipeStream>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<AsyncPipeStream>> {
+        let this = self.get_mut();
+        if this.task.is_none() {
+            let path = this.path.clone();
+            let timeout = this.timeout;
+            this.task = Some(task::spawn_blocking(move || {
+                PipeStreamBuilder::new().overlapped(true).connect_timeout(path, timeout)
+            }));
+        }
+
+        let result = match this.task {
+            Some(ref mut task) => {
+                match Pin::new(task).poll(cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            None => unreachable!(),
+        };
+        this.task = None;
+        Poll::Ready(join_result(result).and_then(AsyncPipeStream::new))
+    }
+}
+
+/// An overlapped `PipeListener` whose connections are accepted without
+/// blocking the executor.
+///
+/// Dropping the listener while an accept is waiting for a client shuts it
+/// down with a `ShutdownHandle`, so the blocking thread is freed. Inside a
+/// runtime the drop returns straight away and the pipe is closed once the
+/// accept has been woken.
+#[derive(Debug)]
+pub struct AsyncPipeListener {
+    // None while an accept holds the listener on the blocking pool.
+    listener: Option<PipeListener>,
+    accept: Option<JoinHandle<(PipeListener, io::Result<PipeStream>)>>,
+    shutdown: ShutdownHandle,
+}
+
+impl AsyncPipeListener {
+    /// Creates a listener for the pipe at `path`, like `PipeListener::bind`
+    /// but opening every instance for overlapped I/O.
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<AsyncPipeListener> {
+        AsyncPipeListener::new(PipeListenerBuilder::new().overlapped(true).bind(path)?)
+    }
+
+    /// Wraps a listener, which must have been bound with
+    /// `PipeListenerBuilder::overlapped`.
+    pub fn new(listener: PipeListener) -> io::Result<AsyncPipeListener> {
+        if !listener.options.overlapped {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "AsyncPipeListener requires an overlapped listener"));
+        }
+
+        Ok(AsyncPipeListener {
+            shutdown: listener.shutdown_handle(),
+            listener: Some(listener),
+            accept: None,
+        })
+    }
+
+    /// Waits for a client to connect. If the future is dropped before then,
+    /// the next call to `accept` picks up the same connection.
+    pub fn accept<'a>(&'a mut self) -> Accept<'a> {
+        Accept { listener: self }
+    }
+
+    /// A stream of the connections made to the listener. It ends only if the
+    /// listener is lost because an accept panicked.
+    pub fn incoming<'a>(&'a mut self) -> Incoming<'a> {
+        Incoming { listener: self }
+    }
+
+    fn is_lost(&self) -> bool {
+        self.listener.is_none() && self.accept.is_none()
+    }
+
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<io::Result<AsyncPipeStream>> {
+        if let Some(mut listener) = self.listener.take() {
+            self.accept = Some(task::spawn_blocking(move || {
+                let result = listener.accept();
+                (listener, result)
+            }));
+        }
+
+        let result = match self.accept {
+            Some(ref mut accept) => {
+                match Pin::new(accept).poll(cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            None => {
+                return Poll::Ready(Err(io::Error::other("listener lost after a panicked accept")))
+            }
+        };
+        self.accept = None;
+        // the listener is lost only if accept panicked, and every later accept
+        // fails.
+        let (listener, result) = result.map_err(io::Error::other)?;
+        self.listener = Some(listener);
+        Poll::Ready(result.and_then(AsyncPipeStream::new))
+    }
+}
+
+impl Drop for AsyncPipeListener {
+    fn drop(&mut self) {
+        if self.accept.is_none() {
+            return;
+        }
+        // signal waits for the accept to notice, so keep it off the executor.
+        // The accept then drops the listener on the blocking pool.
+        let shutdown = self.shutdown.clone();
+        match Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(move || shutdown.signal());
+            }
+            Err(_) => shutdown.signal(),
+        }
+    }
+}
+
+/// The future returned by `AsyncPipeListener::accept`.
+#[derive(Debug)]
+pub struct Accept<'a> {
+    listener: &'a mut AsyncPipeListener,
+}
+
+impl<'a> Future for Accept<'a> {
+    type Output = io::Result<AsyncPipeStream>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<AsyncPipeStream>> {
+        self.get_mut().listener.poll_accept(cx)
+    }
+}
+
+/// The `Stream` returned by `AsyncPipeListener::incoming`.
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    listener: &'a mut AsyncPipeListener,
+}
+
+impl<'a> Stream for Incoming<'a> {
+    type Item = io::Result<AsyncPipeStream>;
+
+    fn poll_next(self: Pin<&mut Self>,
+                 cx: &mut Context)
+                 -> Poll<Option<io::Result<AsyncPipeStream>>> {
+        let listener = &mut self.get_mut().listener;
+        if listener.is_lost() {
+            return Poll::Ready(None);
+        }
+        listener.poll_accept(cx).map(Some)
+    }
+}
+
+fn join_result<T>(result: Result<io::Result<T>, JoinError>) -> io::Result<T> {
+    result.unwrap_or_else(|err| Err(io::Error::other(err)))
+}
@@ -2,107 +2,503 @@
 
 extern crate kernel32;
 extern crate winapi;
+#[cfg(feature = "tokio")]
+extern crate futures_core;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 
 use kernel32::*;
 use winapi::*;
-use std::io::{self, Read, Write};
+use std::error::Error;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::marker::PhantomData;
+use std::io::{self, BufRead, BufReader, BufWriter, IoSlice, IoSliceMut, Read, Seek, SeekFrom,
+              Write};
+use std::net::Shutdown;
 use std::os::windows::prelude::*;
 use std::path::Path;
 use std::ffi::OsString;
 use std::ffi::OsStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
+#[cfg(feature = "tokio")]
+pub mod async_pipe;
+
+const SDDL_REVISION_1: DWORD = 1;
+const NMPWAIT_NOWAIT: DWORD = 1;
+const NMPWAIT_USE_DEFAULT_WAIT: DWORD = 0;
+const HANDLE_FLAG_INHERIT: DWORD = 1;
+const FILE_MODE_INFORMATION: ULONG = 16;
+const FILE_SYNCHRONOUS_IO_ALERT: ULONG = 0x10;
+const FILE_SYNCHRONOUS_IO_NONALERT: ULONG = 0x20;
+
+#[repr(C)]
+struct IoStatusBlock {
+    status: ULONG_PTR,
+    information: ULONG_PTR,
+}
+
+#[link(name = "ntdll")]
+extern "system" {
+    fn NtQueryInformationFile(file: HANDLE,
+                              io_status: *mut IoStatusBlock,
+                              info: LPVOID,
+                              length: ULONG,
+                              class: ULONG)
+                              -> LONG;
+    fn RtlNtStatusToDosError(status: LONG) -> ULONG;
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn ConvertStringSecurityDescriptorToSecurityDescriptorW(string: LPCWSTR,
+                                                            revision: DWORD,
+                                                            descriptor: *mut PSECURITY_DESCRIPTOR,
+                                                            size: PULONG)
+                                                            -> BOOL;
+    fn ImpersonateNamedPipeClient(pipe: HANDLE) -> BOOL;
+    fn RevertToSelf() -> BOOL;
+}
+
+/// One end of a named pipe.
+///
+/// `PipeStream` is `Send` and `Sync`, and `&PipeStream` implements `Read` and
+/// `Write`, so a stream can be shared between threads without a lock; see
+/// `into_shared`. Windows serializes every operation on a handle that wasn't
+/// opened for overlapped I/O, so there a read waiting for data also holds up
+/// writes from other threads. Overlapped streams can read and write at once.
+/// Concurrent reads, or concurrent writes, can interleave their data.
 pub struct PipeStream {
     server_half: bool,
+    access: PipeAccess,
+    overlapped: bool,
     handle: Handle,
+    shared: Arc<Shared>,
+    // where a client was connected to and how, so it can reconnect.
+    path: Option<Arc<OsStr>>,
+    connect_options: Option<PipeStreamBuilder>,
 }
 
-impl PipeStream {
-    fn create_pipe(path: &Path) -> io::Result<HANDLE> {
-        let mut os_str: OsString = path.as_os_str().into();
-        os_str.push("\x00");
-        let u16_slice = os_str.encode_wide().collect::<Vec<u16>>();
+/// Configuration for connecting a `PipeStream` to a server.
+///
+/// ```no_run
+/// use windows_named_pipe::PipeStreamBuilder;
+///
+/// let stream = PipeStreamBuilder::new()
+///     .overlapped(true)
+///     .connect(r"\\.\pipe\example")
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct PipeStreamBuilder {
+    access: PipeAccess,
+    overlapped: bool,
+    write_through: bool,
+    share_mode: DWORD,
+}
+
+impl PipeStreamBuilder {
+    /// Creates a builder with the same configuration as `PipeStream::connect`.
+    pub fn new() -> PipeStreamBuilder {
+        PipeStreamBuilder::default()
+    }
+
+    /// The `PipeAccess` the server created the pipe with, `PipeAccess::Duplex`
+    /// by default. Only the access the client end needs is requested, so a
+    /// client of an inbound pipe opens it write-only and a client of an
+    /// outbound pipe read-only. Connecting with an access the pipe doesn't
+    /// allow fails with the raw OS error `ERROR_ACCESS_DENIED`, the same one
+    /// a server's security descriptor denying the client gives, so when a
+    /// connect is denied check this matches the server's `PipeAccess` first.
+    pub fn access(&mut self, access: PipeAccess) -> &mut Self {
+        self.access = access;
+        self
+    }
+
+    /// Whether to open the pipe for overlapped I/O, false by default.
+    pub fn overlapped(&mut self, overlapped: bool) -> &mut Self {
+        self.overlapped = overlapped;
+        self
+    }
+
+    /// Whether to open the pipe with `FILE_FLAG_WRITE_THROUGH`, false by
+    /// default. Writes to a pipe on another computer then do not complete
+    /// until the data is in the remote pipe's buffer, trading latency for the
+    /// guarantee that nothing written is still cached locally.
+    pub fn write_through(&mut self, write_through: bool) -> &mut Self {
+        self.write_through = write_through;
+        self
+    }
+
+    /// The `FILE_SHARE_*` flags to open the pipe with, 0 by default so the
+    /// handle isn't shared. Only needed for some pipes created by other
+    /// software.
+    pub fn share_mode(&mut self, share: u32) -> &mut Self {
+        self.share_mode = share;
+        self
+    }
+
+    /// Connects to the pipe at `path` using this configuration, waiting up to
+    /// the server's default timeout for a busy instance to become available.
+    ///
+    /// Fails with the raw OS error `ERROR_FILE_NOT_FOUND` if there is no pipe
+    /// at `path`, and `ERROR_PIPE_BUSY` if every instance stayed busy.
+    pub fn connect<P: AsRef<Path>>(&self, path: P) -> io::Result<PipeStream> {
+        let u16_slice = PipeName::from_path(path.as_ref())?.to_wide()?;
+
+        let ok = unsafe { WaitNamedPipeW(u16_slice.as_ptr(), NMPWAIT_USE_DEFAULT_WAIT) };
+        let wait_timed_out = ok == 0 &&
+                             io::Error::last_os_error().raw_os_error() ==
+                             Some(ERROR_SEM_TIMEOUT as i32);
+
+        match self.open_pipe(&u16_slice) {
+            Ok(handle) => Ok(self.stream(handle, path.as_ref())),
+            // an instance that was busy throughout the wait may have been
+            // closed since, which would otherwise look like no pipe at all.
+            Err(ref err) if wait_timed_out &&
+                            err.raw_os_error() == Some(ERROR_FILE_NOT_FOUND as i32) => {
+                Err(io::Error::from_raw_os_error(ERROR_PIPE_BUSY as i32))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Connects to the pipe at `path` using this configuration, waiting up to
+    /// `timeout` for the server to create it or for a busy instance to become
+    /// available. Fails with `io::ErrorKind::TimedOut` if the deadline passes
+    /// first.
+    pub fn connect_timeout<P: AsRef<Path>>(&self,
+                                           path: P,
+                                           timeout: Duration)
+                                           -> io::Result<PipeStream> {
+        let u16_slice = PipeName::from_path(path.as_ref())?.to_wide()?;
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let err = match self.open_pipe(&u16_slice) {
+                Ok(handle) => return Ok(self.stream(handle, path.as_ref())),
+                Err(err) => err,
+            };
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                          "timed out connecting to pipe"));
+            }
+            let remaining = deadline - now;
+
+            match err.raw_os_error().map(|x| x as u32) {
+                Some(ERROR_PIPE_BUSY) => {
+                    let ms = duration_to_ms(remaining);
+                    let _ = unsafe { WaitNamedPipeW(u16_slice.as_ptr(), ms) };
+                }
+                // the server hasn't created the pipe yet.
+                Some(ERROR_FILE_NOT_FOUND) => {
+                    thread::sleep(std::cmp::min(remaining, Duration::from_millis(10)))
+                }
+                _ => return Err(err),
+            }
+        }
+    }
+
+    /// Connects to the pipe at `path` using this configuration, making up to
+    /// `attempts` tries `delay` apart while the connect fails with an error
+    /// `is_retryable` accepts, such as the pipe not existing yet or all of its
+    /// instances being busy. Returns the last error once the attempts are
+    /// used up.
+    pub fn connect_retry<P: AsRef<Path>>(&self,
+                                         path: P,
+                                         attempts: u32,
+                                         delay: Duration)
+                                         -> io::Result<PipeStream> {
+        self.connect_retry_with(path, attempts, |_| delay)
+    }
+
+    /// Like `connect_retry`, but waits between attempts as `backoff` says,
+    /// for example doubling the wait each time so a slow server isn't
+    /// hammered.
+    pub fn connect_backoff<P: AsRef<Path>>(&self,
+                                           path: P,
+                                           attempts: u32,
+                                           backoff: Backoff)
+                                           -> io::Result<PipeStream> {
+        self.connect_retry_with(path, attempts, |failed| backoff.delay(failed))
+    }
+
+    /// Like `connect_retry`, but waits `next_delay(failed)` after the
+    /// `failed`th failed attempt, counting from 1.
+    pub fn connect_retry_with<P, F>(&self,
+                                    path: P,
+                                    attempts: u32,
+                                    mut next_delay: F)
+                                    -> io::Result<PipeStream>
+        where P: AsRef<Path>,
+              F: FnMut(u32) -> Duration
+    {
+        if attempts == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "attempts must be at least 1"));
+        }
+
+        let u16_slice = PipeName::from_path(path.as_ref())?.to_wide()?;
+        let mut attempt = 1;
+        loop {
+            let err = match self.open_pipe(&u16_slice) {
+                Ok(handle) => return Ok(self.stream(handle, path.as_ref())),
+                Err(err) => err,
+            };
+
+            if attempt < attempts && is_retryable(&err) {
+                thread::sleep(next_delay(attempt));
+            } else {
+                return Err(err);
+            }
+            attempt += 1;
+        }
+    }
 
-        let _ = unsafe { WaitNamedPipeW(u16_slice.as_ptr(), 0) };
+    fn open_pipe(&self, u16_slice: &[u16]) -> io::Result<HANDLE> {
+        let mut flags = FILE_ATTRIBUTE_NORMAL;
+        if self.overlapped {
+            flags |= FILE_FLAG_OVERLAPPED;
+        }
+        if self.write_through {
+            flags |= FILE_FLAG_WRITE_THROUGH;
+        }
         let handle = unsafe {
             CreateFileW(u16_slice.as_ptr(),
-                        GENERIC_READ | GENERIC_WRITE,
-                        0,
+                        self.access.client_access(),
+                        self.share_mode,
                         std::ptr::null_mut(),
                         OPEN_EXISTING,
-                        FILE_ATTRIBUTE_NORMAL,
+                        flags,
                         std::ptr::null_mut())
         };
 
         if handle != INVALID_HANDLE_VALUE {
-            Ok(handle)
-        } else {
-            Err(io::Error::last_os_error())
+            return Ok(handle);
         }
-    }
 
-    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<PipeStream> {
-        let handle = PipeStream::create_pipe(path.as_ref())?;
+        Err(io::Error::last_os_error())
+    }
 
-        Ok(PipeStream {
-            handle: Handle { inner: handle },
-            server_half: false,
-        })
+    fn stream(&self, handle: HANDLE, path: &Path) -> PipeStream {
+        let mut stream =
+            PipeStream::new(Handle { inner: handle }, false, self.access, self.overlapped);
+        stream.path = Some(Arc::from(path.as_os_str()));
+        stream.connect_options = Some(*self);
+        stream
     }
 }
 
-impl Drop for PipeStream {
-    fn drop(&mut self) {
-        let _ = unsafe { FlushFileBuffers(self.handle.inner) };
-        if self.server_half {
-            let _ = unsafe { DisconnectNamedPipe(self.handle.inner) };
+/// How long `PipeStreamBuilder::connect_backoff` waits between attempts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// The same wait every time.
+    Fixed(Duration),
+    /// `initial` after the first failure, doubling after each one after that
+    /// up to `max`.
+    Exponential { initial: Duration, max: Duration },
+}
+
+impl Backoff {
+    /// The wait after the `failed`th failed attempt, counting from 1.
+    pub fn delay(&self, failed: u32) -> Duration {
+        match *self {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => {
+                let factor = 1u32.checked_shl(failed.saturating_sub(1)).unwrap_or(u32::MAX);
+                std::cmp::min(initial.checked_mul(factor).unwrap_or(max), max)
+            }
         }
     }
 }
 
-impl Read for PipeStream {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut bytes_read = 0;
-        let ok = unsafe {
-            ReadFile(self.handle.inner,
-                     buf.as_mut_ptr() as LPVOID,
-                     buf.len() as DWORD,
-                     &mut bytes_read,
-                     std::ptr::null_mut())
-        };
+/// Starts at 10ms and doubles up to 1s.
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::Exponential {
+            initial: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+        }
+    }
+}
 
-        if ok != 0 {
-            Ok(bytes_read as usize)
-        } else {
-            match io::Error::last_os_error().raw_os_error().map(|x| x as u32) {
-                Some(ERROR_PIPE_NOT_CONNECTED) => Ok(0),
-                Some(err) => Err(io::Error::from_raw_os_error(err as i32)),
-                _ => panic!(""),
-            }
+impl Default for PipeStreamBuilder {
+    fn default() -> PipeStreamBuilder {
+        PipeStreamBuilder {
+            access: PipeAccess::Duplex,
+            overlapped: false,
+            write_through: false,
+            share_mode: 0,
         }
     }
 }
 
-impl Write for PipeStream {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut bytes_written = 0;
+// State shared between a stream and the clones made of it by `try_clone`.
+#[derive(Debug)]
+struct Shared {
+    handles: AtomicUsize,
+    // the wait mode belongs to the pipe rather than each handle to it.
+    nonblocking: AtomicBool,
+    disconnected: AtomicBool,
+    read_shutdown: AtomicBool,
+    write_shutdown: AtomicBool,
+    lazy_flush: AtomicBool,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    read_timeout: Mutex<Option<Duration>>,
+    write_timeout: Mutex<Option<Duration>>,
+}
+
+impl PipeStream {
+    fn new(handle: Handle, server_half: bool, access: PipeAccess, overlapped: bool) -> PipeStream {
+        PipeStream {
+            server_half,
+            access,
+            overlapped,
+            handle,
+            shared: Arc::new(Shared {
+                handles: AtomicUsize::new(1),
+                nonblocking: AtomicBool::new(false),
+                disconnected: AtomicBool::new(false),
+                read_shutdown: AtomicBool::new(false),
+                write_shutdown: AtomicBool::new(false),
+                lazy_flush: AtomicBool::new(false),
+                bytes_read: AtomicU64::new(0),
+                bytes_written: AtomicU64::new(0),
+                read_timeout: Mutex::new(None),
+                write_timeout: Mutex::new(None),
+            }),
+            path: None,
+            connect_options: None,
+        }
+    }
+
+    /// Connects to the pipe at `path`. See `PipeStreamBuilder::connect` for
+    /// how the raw OS errors tell a missing pipe from a busy one.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<PipeStream> {
+        PipeStream::connect_with_access(path, PipeAccess::Duplex)
+    }
+
+    /// Connects to a pipe created with the given `PipeAccess`, requesting
+    /// only the access the client end of such a pipe needs.
+    pub fn connect_with_access<P: AsRef<Path>>(path: P,
+                                               access: PipeAccess)
+                                               -> io::Result<PipeStream> {
+        PipeStreamBuilder::new().access(access).connect(path)
+    }
+
+    /// Connects to the pipe at `path`, opening it with the `FILE_SHARE_*`
+    /// flags in `share`. See `PipeStreamBuilder::share_mode`.
+    pub fn connect_with_share<P: AsRef<Path>>(path: P, share: u32) -> io::Result<PipeStream> {
+        PipeStreamBuilder::new().share_mode(share).connect(path)
+    }
+
+    /// Connects to the pipe named `pipe` on the computer named `server`, i.e.
+    /// `\\server\pipe\pipe`. Neither name may be empty or contain path
+    /// separators or NULs.
+    pub fn connect_remote(server: &str, pipe: &str) -> io::Result<PipeStream> {
+        PipeStream::connect(PipeName::remote(server, pipe)?)
+    }
+
+    /// Like `connect`, but opens the pipe for overlapped I/O. See
+    /// `read_overlapped` and `write_overlapped`.
+    pub fn connect_overlapped<P: AsRef<Path>>(path: P) -> io::Result<PipeStream> {
+        PipeStreamBuilder::new().overlapped(true).connect(path)
+    }
+
+    /// Connects to the pipe at `path`, waiting up to `timeout` for the server
+    /// to create it or for a busy instance to become available. Fails with
+    /// `io::ErrorKind::TimedOut` if the deadline passes first.
+    pub fn connect_timeout<P: AsRef<Path>>(path: P, timeout: Duration) -> io::Result<PipeStream> {
+        PipeStreamBuilder::new().connect_timeout(path, timeout)
+    }
+
+    /// Connects to the pipe at `path`, making up to `attempts` tries `delay`
+    /// apart while the connect fails with an error `is_retryable` accepts.
+    /// Returns the last error once the attempts are used up.
+    pub fn connect_retry<P: AsRef<Path>>(path: P,
+                                         attempts: u32,
+                                         delay: Duration)
+                                         -> io::Result<PipeStream> {
+        PipeStreamBuilder::new().connect_retry(path, attempts, delay)
+    }
+
+    /// Creates a connected pair of streams, the server end first, for talking
+    /// between threads or to a child process without choosing a pipe name.
+    /// The pipe gets a unique local name, allows only one instance and
+    /// rejects remote clients, and is gone once the pair is returned.
+    pub fn pair() -> io::Result<(PipeStream, PipeStream)> {
+        static PAIRS: AtomicUsize = AtomicUsize::new(0);
+
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos());
+        let name = PipeName::local(&format!("windows-named-pipe-pair-{}-{}-{}",
+                                            std::process::id(),
+                                            PAIRS.fetch_add(1, Ordering::Relaxed),
+                                            nanos))?;
+        let mut listener = PipeListenerBuilder::new()
+            .max_instances(1)
+            .reject_remote_clients(true)
+            .bind(&name)?;
+        let client = PipeStream::connect(&name)?;
+        let server = listener.accept_one()?;
+
+        // someone else could have guessed the name and got in first.
+        if server.client_process_id()? != std::process::id() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      "another process connected to the pipe"));
+        }
+        Ok((server, client))
+    }
+
+    /// Creates a new independently owned handle to the same pipe connection.
+    ///
+    /// A server half is only disconnected once it and all of its clones have
+    /// been dropped.
+    pub fn try_clone(&self) -> io::Result<PipeStream> {
+        let mut handle = std::ptr::null_mut();
         let ok = unsafe {
-            WriteFile(self.handle.inner,
-                      buf.as_ptr() as LPCVOID,
-                      buf.len() as DWORD,
-                      &mut bytes_written,
-                      std::ptr::null_mut())
+            DuplicateHandle(GetCurrentProcess(),
+                            self.handle.inner,
+                            GetCurrentProcess(),
+                            &mut handle,
+                            0,
+                            FALSE,
+                            DUPLICATE_SAME_ACCESS)
         };
 
-        if ok != 0 {
-            Ok(bytes_written as usize)
-        } else {
-            Err(io::Error::last_os_error())
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
         }
+
+        self.shared.handles.fetch_add(1, Ordering::Relaxed);
+        Ok(PipeStream {
+            server_half: self.server_half,
+            access: self.access,
+            overlapped: self.overlapped,
+            handle: Handle { inner: handle },
+            shared: self.shared.clone(),
+            path: self.path.clone(),
+            connect_options: self.connect_options,
+        })
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        let ok = unsafe { FlushFileBuffers(self.handle.inner) };
+    /// Sets whether child processes created with handle inheritance enabled
+    /// inherit this stream's handle. Streams are created non-inheritable, as
+    /// are clones made with `try_clone`, so a server can pass one end of a
+    /// pipe to a child by making just that end inheritable. Converting the
+    /// stream into a `Stdio` doesn't need this.
+    pub fn set_inheritable(&self, inheritable: bool) -> io::Result<()> {
+        let flags = if inheritable { HANDLE_FLAG_INHERIT } else { 0 };
+        let ok = unsafe { SetHandleInformation(self.handle.inner, HANDLE_FLAG_INHERIT, flags) };
 
         if ok != 0 {
             Ok(())
@@ -110,154 +506,3609 @@ impl Write for PipeStream {
             Err(io::Error::last_os_error())
         }
     }
-}
 
-impl AsRawHandle for PipeStream {
-    fn as_raw_handle(&self) -> RawHandle {
-        self.handle.inner
+    /// Wraps the stream in a reference-counted `SharedPipeStream`, whose
+    /// clones can all read and write.
+    pub fn into_shared(self) -> SharedPipeStream {
+        SharedPipeStream { stream: Arc::new(self) }
     }
-}
 
-impl IntoRawHandle for PipeStream {
-    fn into_raw_handle(self) -> RawHandle {
-        self.handle.inner
+    /// Splits the stream into halves that can be moved to different threads.
+    ///
+    /// The halves only read and write concurrently if the stream was opened
+    /// for overlapped I/O. Otherwise Windows runs one operation on the handle
+    /// at a time, so a read waiting for data blocks every write until it
+    /// finishes, and a reader waiting for the reply to a write deadlocks.
+    pub fn split(self) -> (ReadHalf, WriteHalf) {
+        let stream = Arc::new(self);
+        (ReadHalf { stream: stream.clone() }, WriteHalf { stream })
     }
-}
 
-impl FromRawHandle for PipeStream {
-    unsafe fn from_raw_handle(handle: RawHandle) -> Self {
-        PipeStream {
-            handle: Handle { inner: handle },
-            server_half: false,
+    /// Starts an overlapped read of up to `buf.len()` bytes into `buf`. The
+    /// stream must have been opened for overlapped I/O.
+    pub fn read_overlapped<'a>(&'a self, buf: Vec<u8>) -> io::Result<PendingIo<'a>> {
+        self.check_readable()?;
+        self.check_overlapped()?;
+        PendingIo::start(&self.handle, buf, |buf, overlapped| unsafe {
+            ReadFile(self.handle.inner,
+                     buf.as_mut_ptr() as LPVOID,
+                     buf.len() as DWORD,
+                     std::ptr::null_mut(),
+                     overlapped)
+        })
+    }
+
+    /// Returns an event that is signaled while the stream has data to read or
+    /// its other end has gone away, for waiting on alongside other handles
+    /// with `WaitForMultipleObjects`. The stream must have been opened for
+    /// overlapped I/O. See `ReadinessEvent`.
+    pub fn readiness_event<'a>(&'a self) -> io::Result<ReadinessEvent<'a>> {
+        self.check_readable()?;
+        self.check_overlapped()?;
+        let event = create_event()?;
+        let mut readiness = ReadinessEvent {
+            handle: &self.handle,
+            state: Box::new(IoState {
+                overlapped: new_overlapped(&event),
+                event,
+                buf: Vec::new(),
+            }),
+            pending: false,
+        };
+        readiness.arm()?;
+        Ok(readiness)
+    }
+
+    /// Starts an overlapped write of the contents of `buf`. The stream must
+    /// have been opened for overlapped I/O.
+    pub fn write_overlapped<'a>(&'a self, buf: Vec<u8>) -> io::Result<PendingIo<'a>> {
+        self.check_writable()?;
+        self.check_overlapped()?;
+        PendingIo::start(&self.handle, buf, |buf, overlapped| unsafe {
+            WriteFile(self.handle.inner,
+                      buf.as_ptr() as LPCVOID,
+                      buf.len() as DWORD,
+                      std::ptr::null_mut(),
+                      overlapped)
+        })
+    }
+
+    /// Moves the pipe into or out of nonblocking mode. In nonblocking mode
+    /// reads with no data available and writes that can't make progress
+    /// fail with `io::ErrorKind::WouldBlock`.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        // keep the read mode, which is set by the same call.
+        let mut mode = self.state_flags()? & PIPE_READMODE_MESSAGE;
+        if nonblocking {
+            mode |= PIPE_NOWAIT;
+        }
+        let ok = unsafe {
+            SetNamedPipeHandleState(self.handle.inner,
+                                    &mut mode,
+                                    std::ptr::null_mut(),
+                                    std::ptr::null_mut())
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
         }
+
+        self.shared.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-pub struct PipeListener {
-    path: Vec<u16>,
-    next_pipe: Handle,
-}
+    /// Switches how reads from this end of the pipe are delimited. Message
+    /// read mode is only available on message-type pipes, and asking for it
+    /// on a byte-type pipe fails with `io::ErrorKind::InvalidInput`.
+    pub fn set_read_mode(&self, mode: PipeMode) -> io::Result<()> {
+        self.set_state(mode, std::ptr::null_mut(), std::ptr::null_mut())
+    }
 
-    fn to_u16s<S: AsRef<OsStr>>(s: S) -> io::Result<Vec<u16>> {
-        fn inner(s: &OsStr) -> io::Result<Vec<u16>> {
-            let mut maybe_result: Vec<u16> = s.encode_wide().collect();
-            if maybe_result.iter().any(|&u| u == 0) {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                          "strings passed to WinAPI cannot contain NULs"));
-            }
-            maybe_result.push(0);
-            Ok(maybe_result)
+    /// Sets the read mode along with how the client end of a pipe to another
+    /// computer collects writes before sending them: at most
+    /// `max_collection_count` bytes are buffered, for at most
+    /// `collect_data_timeout`. Larger values mean fewer network round trips
+    /// at the cost of latency.
+    ///
+    /// Only valid on the client end. Windows rejects the call with
+    /// `ERROR_INVALID_PARAMETER` when the server is on the same computer, and
+    /// ignores the collection settings for write-through pipes.
+    pub fn configure_client_state(&self,
+                                  mode: PipeMode,
+                                  max_collection_count: u32,
+                                  collect_data_timeout: Duration)
+                                  -> io::Result<()> {
+        if self.server_half {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "only valid on the client end of a pipe"));
         }
-        inner(s.as_ref())
+        if max_collection_count == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "max_collection_count must be at least 1"));
+        }
+
+        let mut count = max_collection_count;
+        let mut timeout = duration_to_ms(collect_data_timeout);
+        self.set_state(mode, &mut count, &mut timeout)
     }
 
-impl PipeListener {
-    fn create_pipe(path: &[u16], first: bool) -> io::Result<Handle> {
-        let mut access_flags = PIPE_ACCESS_DUPLEX;
-        if first {
-            access_flags |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+    /// Tunes the client end of a pipe to another computer for throughput:
+    /// writes are collected into batches of up to 4KiB for at most 10ms
+    /// before being sent, keeping the current read mode. See
+    /// `configure_client_state` to pick the values yourself.
+    ///
+    /// Does nothing when the server is on the same computer, where writes
+    /// aren't collected. Fails with `io::ErrorKind::InvalidInput` on the
+    /// server end.
+    pub fn optimize_for_remote(&self) -> io::Result<()> {
+        let mode = self.read_mode()?;
+        match self.configure_client_state(mode, 4096, Duration::from_millis(10)) {
+            Err(ref err) if err.raw_os_error() == Some(ERROR_INVALID_PARAMETER as i32) => Ok(()),
+            result => result,
         }
-        let handle = unsafe {
-            CreateNamedPipeW(path.as_ptr(),
-                             access_flags,
-                             PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT,
-                             PIPE_UNLIMITED_INSTANCES,
-                             65536,
-                             65536,
-                             50,
-                             std::ptr::null_mut())
-        };
+    }
 
-        if handle != INVALID_HANDLE_VALUE {
-            Ok(Handle { inner: handle })
+    fn set_state(&self, mode: PipeMode, count: LPDWORD, timeout: LPDWORD) -> io::Result<()> {
+        if mode == PipeMode::Message && !self.info()?.is_message_type() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "message read mode requires a message-type pipe"));
+        }
+
+        // keep the wait mode, which is set by the same call.
+        let mut state = self.state_flags()? & PIPE_NOWAIT | mode.read_mode();
+        let ok = unsafe { SetNamedPipeHandleState(self.handle.inner, &mut state, count, timeout) };
+
+        if ok != 0 {
+            Ok(())
         } else {
             Err(io::Error::last_os_error())
         }
     }
 
-    fn connect_pipe(handle: &Handle) -> io::Result<()> {
-        let result = unsafe { ConnectNamedPipe(handle.inner, std::ptr::null_mut()) };
+    /// How reads from this end of the pipe are currently delimited.
+    pub fn read_mode(&self) -> io::Result<PipeMode> {
+        if self.state_flags()? & PIPE_READMODE_MESSAGE != 0 {
+            Ok(PipeMode::Message)
+        } else {
+            Ok(PipeMode::Byte)
+        }
+    }
+
+    // The PIPE_NOWAIT and PIPE_READMODE_MESSAGE flags currently set.
+    fn state_flags(&self) -> io::Result<DWORD> {
+        let mut state = 0;
+        let ok = unsafe {
+            GetNamedPipeHandleStateW(self.handle.inner,
+                                     &mut state,
+                                     std::ptr::null_mut(),
+                                     std::ptr::null_mut(),
+                                     std::ptr::null_mut(),
+                                     std::ptr::null_mut(),
+                                     0)
+        };
 
-        if result != 0 {
-            Ok(())
+        if ok != 0 {
+            Ok(state)
         } else {
-            match io::Error::last_os_error().raw_os_error().map(|x| x as u32) {
-                Some(ERROR_PIPE_CONNECTED) => Ok(()),
-                Some(err) => Err(io::Error::from_raw_os_error(err as i32)),
-                _ => panic!(""),
-            }
+            Err(io::Error::last_os_error())
         }
     }
 
-    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-        let path = to_u16s(path.as_ref().as_os_str())?;
-        let handle = PipeListener::create_pipe(&path, true)?;
-        Ok(PipeListener {
-            path: path,
-            next_pipe: handle,
-        })
+    /// Writes `out` as a single message and reads the reply into `in_buf`,
+    /// returning the length of the reply. The pipe must be in message read
+    /// mode.
+    ///
+    /// If the reply doesn't fit in `in_buf` this fails with the raw OS error
+    /// `ERROR_MORE_DATA` with the start of the reply in `in_buf`, and the
+    /// rest of it can be read with `read`.
+    pub fn transact(&self, out: &[u8], in_buf: &mut [u8]) -> io::Result<usize> {
+        self.check_readable()?;
+        self.check_writable()?;
+        self.check_connected()?;
+        if self.state_flags()? & PIPE_READMODE_MESSAGE == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "transact requires a pipe in message read mode"));
+        }
+
+        let mut bytes_read = 0;
+        run_io(&self.handle, self.overlapped, None, &mut bytes_read, |bytes, overlapped| {
+            unsafe {
+                TransactNamedPipe(self.handle.inner,
+                                  out.as_ptr() as LPVOID,
+                                  out.len() as DWORD,
+                                  in_buf.as_mut_ptr() as LPVOID,
+                                  in_buf.len() as DWORD,
+                                  bytes,
+                                  overlapped)
+            }
+        })?;
+
+        Ok(bytes_read as usize)
     }
 
-    pub fn accept(&mut self) -> io::Result<PipeStream> {
-        let handle = std::mem::replace(&mut self.next_pipe,
-                                       PipeListener::create_pipe(&self.path, false)?);
+    /// Sets a timeout for reads, after which they fail with
+    /// `io::ErrorKind::TimedOut` and the stream stays usable. `None` means
+    /// reads block forever. Timeouts require a stream opened for overlapped
+    /// I/O.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_timeout(&self.shared.read_timeout, timeout)
+    }
 
-        PipeListener::connect_pipe(&handle)?;
+    /// Sets a timeout for writes, with the same rules as `set_read_timeout`.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_timeout(&self.shared.write_timeout, timeout)
+    }
 
-        Ok(PipeStream {
-            handle: handle,
-            server_half: true,
-        })
+    /// Returns the timeout set by `set_read_timeout`.
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.shared.read_timeout.lock().unwrap())
     }
 
-    pub fn incoming<'a>(&'a mut self) -> Incoming<'a> {
-        Incoming { listener: self }
+    /// Returns the timeout set by `set_write_timeout`.
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.shared.write_timeout.lock().unwrap())
     }
-}
 
-pub struct Incoming<'a>
-{
-    listener: &'a mut PipeListener,
-}
+    /// Reads into `buf`, giving up at `deadline` whatever the read timeout.
+    /// Returns the data read if any arrived before then, and otherwise fails
+    /// with `io::ErrorKind::TimedOut`. The stream must have been opened for
+    /// overlapped I/O.
+    pub fn read_deadline(&self, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        self.check_overlapped()?;
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        self.read_message_with_timeout(buf, Some(timeout)).map(|(bytes_read, _)| bytes_read)
+    }
 
-impl<'a> IntoIterator for &'a mut PipeListener {
-    type Item = io::Result<PipeStream>;
-    type IntoIter = Incoming<'a>;
+    /// Writes some of `buf`, giving up at `deadline` whatever the write
+    /// timeout. If the write is cancelled after part of `buf` was written
+    /// this returns how much was, and otherwise fails with
+    /// `io::ErrorKind::TimedOut`. The stream must have been opened for
+    /// overlapped I/O.
+    pub fn write_deadline(&self, buf: &[u8], deadline: Instant) -> io::Result<usize> {
+        self.check_overlapped()?;
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        self.write_with_timeout(buf, Some(timeout))
+    }
 
-    fn into_iter(self) -> Incoming<'a> {
-        self.incoming()
+    fn set_timeout(&self,
+                   slot: &Mutex<Option<Duration>>,
+                   timeout: Option<Duration>)
+                   -> io::Result<()> {
+        if timeout == Some(Duration::from_secs(0)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "cannot set a 0 duration timeout"));
+        }
+        if timeout.is_some() {
+            self.check_overlapped()?;
+        }
+
+        *slot.lock().unwrap() = timeout;
+        Ok(())
     }
-}
 
-impl<'a> Iterator for Incoming<'a> {
-    type Item = io::Result<PipeStream>;
+    fn is_nonblocking(&self) -> bool {
+        self.shared.nonblocking.load(Ordering::Relaxed)
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        Some(self.listener.accept())
+    /// Chooses what `Write::flush` does for this stream and its clones.
+    ///
+    /// By default `flush` calls `FlushFileBuffers`, which blocks until the
+    /// other end has read everything written so far. With `lazy` set it
+    /// returns straight away instead, since every write has already been
+    /// handed to the pipe by the time it returns. `sync_data` always waits,
+    /// and so does dropping or closing the stream, so nothing is lost when a
+    /// server half disconnects.
+    pub fn set_lazy_flush(&self, lazy: bool) {
+        self.shared.lazy_flush.store(lazy, Ordering::Relaxed);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::thread;
-    use super::*;
+    /// Blocks until the other end has read everything written to the pipe,
+    /// however `set_lazy_flush` is set.
+    pub fn sync_data(&self) -> io::Result<()> {
+        self.flush_inner()
+    }
 
-    macro_rules! or_panic {
-        ($e:expr) => {
-            match $e {
-                Ok(e) => e,
-                Err(e) => {
-                    panic!("{}", e);
-                },
+    /// Blocks until the other end has read everything written to the pipe.
+    /// A server can call this before disconnecting so the client doesn't
+    /// lose data it hasn't read yet. The same as `sync_data`.
+    pub fn drain(&self) -> io::Result<()> {
+        self.flush_inner()
+    }
+
+    /// Like `drain`, but gives up with `io::ErrorKind::TimedOut` if the other
+    /// end hasn't read everything within `timeout`. Pipes have no overlapped
+    /// flush, so the flush runs on a separate thread, which is cancelled on
+    /// timeout.
+    pub fn drain_timeout(&self, timeout: Duration) -> io::Result<()> {
+        let handle = self.handle.inner as usize;
+        let (tx, rx) = mpsc::channel();
+        let flusher = thread::spawn(move || {
+            let ok = unsafe { FlushFileBuffers(handle as HANDLE) };
+            let _ = tx.send(if ok != 0 { Ok(()) } else { Err(io::Error::last_os_error()) });
+        });
+
+        let result = match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // the flush may not have started when it is first cancelled,
+                // so keep cancelling until it gives up.
+                let result = loop {
+                    let _ = unsafe { CancelSynchronousIo(flusher.as_raw_handle()) };
+                    match rx.recv_timeout(DRAIN_CANCEL_INTERVAL) {
+                        Ok(result) => break Some(result),
+                        Err(mpsc::RecvTimeoutError::Timeout) => {}
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break None,
+                    }
+                };
+                match result {
+                    // it finished just as the time ran out.
+                    Some(Ok(())) => Ok(()),
+                    Some(Err(_)) => {
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "timed out draining pipe"))
+                    }
+                    None => Err(drain_thread_lost()),
+                }
             }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(drain_thread_lost()),
+        };
+        // the handle must outlive the flush.
+        let _ = flusher.join();
+        result
+    }
+
+    // What Write::flush does, see set_lazy_flush.
+    fn flush_write(&self) -> io::Result<()> {
+        if self.shared.lazy_flush.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            self.flush_inner()
         }
     }
 
-    #[test]
-    fn basic() {
-        let socket_path = Path::new("//./pipe/basicsock");
-        println!("{:?}", socket_path);
+    /// Writes every slice in `bufs`, in order, without flushing. Each call to
+    /// `write_vectored` may write only part of the data, so this keeps going
+    /// from wherever the last write stopped, and `bufs` is left advanced past
+    /// whatever was written. Fails with `io::ErrorKind::WriteZero` if the
+    /// pipe stops accepting data.
+    pub fn write_all_vectored(&self, mut bufs: &mut [IoSlice]) -> io::Result<()> {
+        // skip leading empty slices, so an empty write isn't taken for no
+        // progress.
+        IoSlice::advance_slices(&mut bufs, 0);
+        while !bufs.is_empty() {
+            match self.write_vectored_inner(bufs) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "failed to write whole buffer"));
+                }
+                Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Cancels every read, write or connect in progress on this pipe, from
+    /// any thread, so a supervisor can unblock a worker stuck in `read`.
+    /// Unlike `CancelIo`, which only reaches operations the calling thread
+    /// issued, this uses `CancelIoEx` and so also stops blocking calls made
+    /// on other threads. Cancelled operations fail with the raw OS error
+    /// `ERROR_OPERATION_ABORTED`. Succeeds if nothing was in progress.
+    pub fn cancel_io(&self) -> io::Result<()> {
+        let ok = unsafe { CancelIoEx(self.handle.inner, std::ptr::null_mut()) };
+        if ok != 0 {
+            return Ok(());
+        }
+
+        let err = io::Error::last_os_error();
+        match err.raw_os_error().map(|x| x as u32) {
+            Some(ERROR_NOT_FOUND) => Ok(()),
+            _ => Err(err),
+        }
+    }
+
+    // Cancels the one operation whose OVERLAPPED is at `overlapped`, from any
+    // thread, leaving the others on the handle running. The operation must
+    // not have been freed yet.
+    #[cfg(feature = "tokio")]
+    fn cancel_overlapped(&self, overlapped: usize) {
+        unsafe {
+            CancelIoEx(self.handle.inner, overlapped as LPOVERLAPPED);
+        }
+    }
+
+    /// Whether this is the server end of the pipe, i.e. it was returned by
+    /// `PipeListener::accept`. Server-only methods such as `disconnect` and
+    /// `client_process_id` fail on the client end.
+    pub fn is_server(&self) -> bool {
+        self.server_half
+    }
+
+    /// Associates the pipe with the I/O completion port `port`, so that
+    /// overlapped operations started on the raw handle post a completion
+    /// packet with the key `key` to the port. The stream must have been
+    /// opened for overlapped I/O, and every read or write issued on the raw
+    /// handle must then pass its own `OVERLAPPED` structure. The stream's own
+    /// methods keep working and don't post packets to the port.
+    ///
+    /// A handle can only ever be associated with one port.
+    // a bad port handle is an error from CreateIoCompletionPort, not UB.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn register_iocp(&self, port: RawHandle, key: usize) -> io::Result<()> {
+        self.check_overlapped()?;
+        let result = unsafe {
+            CreateIoCompletionPort(self.handle.inner, port, key as ULONG_PTR, 0)
+        };
+
+        if !result.is_null() {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Whether the stream was opened for overlapped I/O.
+    pub fn is_overlapped(&self) -> bool {
+        self.overlapped
+    }
+
+    fn check_overlapped(&self) -> io::Result<()> {
+        if self.overlapped {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                               "the pipe was not opened for overlapped I/O"))
+        }
+    }
+
+    /// Copies data waiting in the pipe into `buf` without removing it from
+    /// the pipe, returning the number of bytes copied. Never blocks.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_readable()?;
+        let (bytes_read, _, _) = self.peek_named_pipe(buf)?;
+        Ok(bytes_read as usize)
+    }
+
+    /// Blocks until there is data to read or the other end has gone away,
+    /// returning `Ok(true)`, or until `timeout` passes, returning `Ok(false)`.
+    /// Nothing is read, so the next read won't block.
+    ///
+    /// Overlapped streams are waited on with an event, like the free function
+    /// `wait_readable`. Other streams are polled with `PeekNamedPipe` every
+    /// millisecond, which the system timer usually stretches to about 15ms.
+    pub fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        self.check_readable()?;
+        if self.shared.read_shutdown.load(Ordering::Acquire) {
+            return Ok(true);
+        }
+
+        if self.overlapped {
+            return match ::wait_readable(&[self], timeout) {
+                Ok(_) => Ok(true),
+                Err(ref err) if err.kind() == io::ErrorKind::TimedOut => Ok(false),
+                Err(err) => Err(err),
+            };
+        }
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            match self.peek_named_pipe(&mut []) {
+                Ok((_, available, _)) if available > 0 => return Ok(true),
+                Ok(_) => {}
+                Err(err) => match err.raw_os_error().map(|x| x as u32) {
+                    // a read would return the end of the stream at once.
+                    Some(ERROR_BROKEN_PIPE) |
+                    Some(ERROR_PIPE_NOT_CONNECTED) => return Ok(true),
+                    _ => return Err(err),
+                },
+            }
+
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(false);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Whether the other end still seems to be connected, checked with a
+    /// zero-length `PeekNamedPipe` that doesn't consume any data. This is
+    /// only a snapshot, the peer can go away straight after. A closed or
+    /// disconnected peer, or a server half still waiting for a client, is
+    /// reported as not connected.
+    ///
+    /// The peek needs read access, so on an end that is only writable any
+    /// failure other than those is taken as still connected.
+    pub fn is_connected(&self) -> bool {
+        if self.shared.disconnected.load(Ordering::Acquire) {
+            return false;
+        }
+
+        match self.peek_named_pipe(&mut []) {
+            Ok(_) => true,
+            Err(err) => match err.raw_os_error().map(|x| x as u32) {
+                Some(ERROR_BROKEN_PIPE) |
+                Some(ERROR_PIPE_NOT_CONNECTED) |
+                Some(ERROR_PIPE_LISTENING) |
+                Some(ERROR_NO_DATA) => false,
+                _ => !self.access.readable(self.server_half),
+            },
+        }
+    }
+
+    /// Returns the total number of bytes waiting to be read. Never blocks.
+    pub fn bytes_available(&self) -> io::Result<u32> {
+        self.check_readable()?;
+        let (_, available, _) = self.peek_named_pipe(&mut [])?;
+        Ok(available)
+    }
+
+    /// Returns the number of bytes left in the next message of a
+    /// message-mode pipe, or 0 for byte-mode pipes. Never blocks.
+    pub fn message_bytes_left(&self) -> io::Result<u32> {
+        self.check_readable()?;
+        let (_, _, left) = self.peek_named_pipe(&mut [])?;
+        Ok(left)
+    }
+
+    /// Reads from the pipe, returning the number of bytes read and whether
+    /// the rest of the current message is still waiting in the pipe because
+    /// it didn't fit in `buf`. The flag is only ever set in message read
+    /// mode, and `message_bytes_left` gives the size of the remainder.
+    pub fn read_message(&self, buf: &mut [u8]) -> io::Result<(usize, bool)> {
+        self.read_message_with_timeout(buf, self.read_timeout()?)
+    }
+
+    fn read_message_with_timeout(&self,
+                                 buf: &mut [u8],
+                                 timeout: Option<Duration>)
+                                 -> io::Result<(usize, bool)> {
+        self.check_readable()?;
+        if self.shared.read_shutdown.load(Ordering::Acquire) {
+            return Ok((0, false));
+        }
+        self.check_connected()?;
+        let mut bytes_read = 0;
+        let result = run_io(&self.handle, self.overlapped, timeout, &mut bytes_read, |bytes, ov| {
+            unsafe {
+                ReadFile(self.handle.inner,
+                         buf.as_mut_ptr() as LPVOID,
+                         buf.len() as DWORD,
+                         bytes,
+                         ov)
+            }
+        });
+
+        self.shared.bytes_read.fetch_add(bytes_read as u64, Ordering::Relaxed);
+        match result {
+            Ok(()) => Ok((bytes_read as usize, false)),
+            Err(err) => match err.raw_os_error().map(|x| x as u32) {
+                // the other end disconnected or closed its handle, after any
+                // data it wrote has been read.
+                Some(ERROR_PIPE_NOT_CONNECTED) |
+                Some(ERROR_BROKEN_PIPE) => Ok((0, false)),
+                // a message pipe had more data than fit in `buf`, the rest of
+                // the message is returned by subsequent reads.
+                Some(ERROR_MORE_DATA) => Ok((bytes_read as usize, true)),
+                Some(ERROR_NO_DATA) if self.is_nonblocking() => {
+                    Err(io::Error::new(io::ErrorKind::WouldBlock, "no data available"))
+                }
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// The number of bytes read and written through the stream's `Read` and
+    /// `Write` impls so far, counting clones made with `try_clone` and the
+    /// halves from `split` together.
+    pub fn stats(&self) -> PipeStats {
+        PipeStats {
+            bytes_read: self.shared.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.shared.bytes_written.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns how many instances of the pipe currently exist, including ones
+    /// a listener has created for clients that haven't connected yet.
+    pub fn current_instances(&self) -> io::Result<u32> {
+        let mut instances = 0;
+        let ok = unsafe {
+            GetNamedPipeHandleStateW(self.handle.inner,
+                                     std::ptr::null_mut(),
+                                     &mut instances,
+                                     std::ptr::null_mut(),
+                                     std::ptr::null_mut(),
+                                     std::ptr::null_mut(),
+                                     0)
+        };
+
+        if ok != 0 {
+            Ok(instances)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Queries everything `GetNamedPipeHandleState` reports about this end of
+    /// the pipe. Fields Windows can't provide for this handle are `None`: the
+    /// collection settings only exist on the client end, and the client's
+    /// user name only on the server end once the client has written.
+    pub fn handle_state(&self) -> io::Result<HandleState> {
+        let null = std::ptr::null_mut();
+        let mut state = 0;
+        let mut instances = 0;
+        self.query_handle_state(&mut state, &mut instances, null, null, &mut [])?;
+
+        let mut count = 0;
+        let mut timeout = 0;
+        let collection = if self.server_half {
+            None
+        } else {
+            self.query_handle_state(null, null, &mut count, &mut timeout, &mut []).ok()
+        };
+
+        // UNLEN plus the terminator.
+        let mut name = [0u16; 257];
+        let user_name = if self.server_half {
+            self.query_handle_state(null, null, null, null, &mut name).ok()
+        } else {
+            None
+        };
+
+        Ok(HandleState {
+            nonblocking: state & PIPE_NOWAIT != 0,
+            read_mode: if state & PIPE_READMODE_MESSAGE != 0 {
+                PipeMode::Message
+            } else {
+                PipeMode::Byte
+            },
+            current_instances: instances,
+            max_collection_count: collection.map(|()| count),
+            collect_data_timeout: collection.map(|()| Duration::from_millis(timeout as u64)),
+            user_name: user_name.map(|()| {
+                let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+                OsString::from_wide(&name[..len])
+            }),
+        })
+    }
+
+    // An empty `name` leaves the user name out of the query.
+    fn query_handle_state(&self,
+                          state: *mut DWORD,
+                          instances: *mut DWORD,
+                          count: *mut DWORD,
+                          timeout: *mut DWORD,
+                          name: &mut [u16])
+                          -> io::Result<()> {
+        let name_ptr = if name.is_empty() {
+            std::ptr::null_mut()
+        } else {
+            name.as_mut_ptr()
+        };
+        let ok = unsafe {
+            GetNamedPipeHandleStateW(self.handle.inner,
+                                     state,
+                                     instances,
+                                     count,
+                                     timeout,
+                                     name_ptr,
+                                     name.len() as DWORD)
+        };
+
+        if ok != 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Queries how the pipe this stream is connected to was configured.
+    pub fn info(&self) -> io::Result<PipeInfo> {
+        let mut info = PipeInfo {
+            flags: 0,
+            out_buffer_size: 0,
+            in_buffer_size: 0,
+            max_instances: 0,
+        };
+        let ok = unsafe {
+            GetNamedPipeInfo(self.handle.inner,
+                             &mut info.flags,
+                             &mut info.out_buffer_size,
+                             &mut info.in_buffer_size,
+                             &mut info.max_instances)
+        };
+
+        if ok != 0 {
+            Ok(info)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Makes the calling thread act with the security context of the
+    /// connected client until the returned guard is dropped. Only valid on a
+    /// connected server half, and only after something has been read from
+    /// the client.
+    pub fn impersonate_client(&self) -> io::Result<ImpersonationGuard> {
+        self.check_server()?;
+        self.check_connected()?;
+        let ok = unsafe { ImpersonateNamedPipeClient(self.handle.inner) };
+
+        if ok != 0 {
+            Ok(ImpersonationGuard { _thread: PhantomData })
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Returns the process id of the connected client. Only valid on the
+    /// server half.
+    pub fn client_process_id(&self) -> io::Result<u32> {
+        self.check_server()?;
+        let mut pid = 0;
+        let ok = unsafe { GetNamedPipeClientProcessId(self.handle.inner, &mut pid) };
+
+        if ok != 0 {
+            Ok(pid)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Returns the name of the computer the connected client is running on.
+    /// Only valid on the server half.
+    ///
+    /// Windows only records the name for clients on other computers, so for
+    /// a local client this fails with the raw OS error `ERROR_PIPE_LOCAL`.
+    pub fn client_computer_name(&self) -> io::Result<OsString> {
+        self.check_server()?;
+        // enough for any DNS name, but grow the buffer if Windows asks.
+        let mut name = vec![0u16; 256];
+        loop {
+            let ok = unsafe {
+                GetNamedPipeClientComputerNameW(self.handle.inner,
+                                                name.as_mut_ptr(),
+                                                (name.len() * 2) as ULONG)
+            };
+            if ok != 0 {
+                let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+                return Ok(OsString::from_wide(&name[..len]));
+            }
+
+            let err = io::Error::last_os_error();
+            match err.raw_os_error().map(|x| x as u32) {
+                Some(ERROR_MORE_DATA) |
+                Some(ERROR_INSUFFICIENT_BUFFER) if name.len() < 32768 => {
+                    let len = name.len() * 2;
+                    name.resize(len, 0);
+                }
+                _ => return Err(err),
+            }
+        }
+    }
+
+    /// Returns the process id of the server end of the pipe. Clients can
+    /// compare this against a known pid to check they reached the expected
+    /// server.
+    pub fn server_process_id(&self) -> io::Result<u32> {
+        let mut pid = 0;
+        let ok = unsafe { GetNamedPipeServerProcessId(self.handle.inner, &mut pid) };
+
+        if ok != 0 {
+            Ok(pid)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Disconnects the client from a server half. Reads and writes fail with
+    /// `io::ErrorKind::NotConnected` until a new client is connected with
+    /// `connect_client`.
+    pub fn disconnect(&mut self) -> io::Result<()> {
+        self.check_server()?;
+        self.disconnect_inner()
+    }
+
+    fn disconnect_inner(&self) -> io::Result<()> {
+        let ok = unsafe { DisconnectNamedPipe(self.handle.inner) };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.shared.disconnected.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Shuts down the read half, the write half, or both, for this stream
+    /// and its clones.
+    ///
+    /// Pipes can't be half closed like sockets, so this is emulated. After
+    /// `Shutdown::Read`, reads return `Ok(0)`, but the peer can still write
+    /// until the pipe buffer is full. `Shutdown::Write` flushes the stream
+    /// and makes later writes fail with `io::ErrorKind::BrokenPipe`, but the
+    /// peer only sees the end of the stream once the pipe is closed or
+    /// disconnected. Only `Shutdown::Both` on a server half is visible to the
+    /// peer straight away, since it also disconnects the client.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read && !self.shared.write_shutdown.load(Ordering::Acquire) &&
+           self.access.writable(self.server_half) && self.check_connected().is_ok() {
+            self.flush_inner()?;
+        }
+
+        if how != Shutdown::Write {
+            self.shared.read_shutdown.store(true, Ordering::Release);
+        }
+        if how != Shutdown::Read {
+            self.shared.write_shutdown.store(true, Ordering::Release);
+        }
+        if how == Shutdown::Both && self.server_half && self.check_connected().is_ok() {
+            self.disconnect_inner()?;
+        }
+        Ok(())
+    }
+
+    /// Blocks until a new client connects to a server half that was
+    /// disconnected with `disconnect`.
+    pub fn connect_client(&mut self) -> io::Result<()> {
+        self.check_server()?;
+        PipeListener::connect_pipe(&self.handle, self.overlapped, None)?;
+        self.shared.read_shutdown.store(false, Ordering::Release);
+        self.shared.write_shutdown.store(false, Ordering::Release);
+        self.shared.disconnected.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// The path the stream was connected to, or for a server half the path
+    /// its listener was bound to. `None` for streams made from a raw handle.
+    pub fn path(&self) -> Option<&OsStr> {
+        self.path.as_deref()
+    }
+
+    /// Connects a client stream to the pipe it was connected to before, for
+    /// example after the server restarted, then closes the old connection.
+    /// The access and other `PipeStreamBuilder` options, the read mode,
+    /// nonblocking mode, `set_lazy_flush` and the read and write timeouts
+    /// carry over. The old connection is closed without waiting for the
+    /// server to read what was written to it. On error the stream is left
+    /// as it was.
+    ///
+    /// Only streams opened by one of the `connect` functions can reconnect,
+    /// others fail with `io::ErrorKind::InvalidInput`. Like
+    /// `PipeStreamBuilder::connect`, this fails with `ERROR_FILE_NOT_FOUND`
+    /// if the server hasn't created the pipe again yet.
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        let (path, options) = match (self.path.clone(), self.connect_options) {
+            (Some(path), Some(options)) => (path, options),
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "only a stream opened with connect can reconnect"))
+            }
+        };
+        // the old connection may be broken, in which case use the default.
+        let mode = self.read_mode().unwrap_or(PipeMode::Byte);
+
+        let stream = options.connect(&*path)?;
+        if mode == PipeMode::Message {
+            stream.set_read_mode(mode)?;
+        }
+        if self.is_nonblocking() {
+            stream.set_nonblocking(true)?;
+        }
+        stream.set_lazy_flush(self.shared.lazy_flush.load(Ordering::Relaxed));
+        stream.set_read_timeout(self.read_timeout()?)?;
+        stream.set_write_timeout(self.write_timeout()?)?;
+        // a server that went away can't read what is left, so don't wait for
+        // it to.
+        let _ = std::mem::replace(self, stream).close_inner(false);
+        Ok(())
+    }
+
+    /// Like `FromRawHandle::from_raw_handle`, but for the server end of a
+    /// pipe, so server-only methods work and dropping the stream disconnects
+    /// the client.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be an open handle to the server end of a pipe that is
+    /// owned by nothing else, and must not have been opened for overlapped
+    /// I/O.
+    pub unsafe fn from_raw_handle_server(handle: RawHandle) -> PipeStream {
+        PipeStream::new(Handle { inner: handle }, true, PipeAccess::Duplex, false)
+    }
+
+    /// Like `from_raw_handle`, but first checks that `handle` refers to a
+    /// pipe, returning an `InvalidInput` error if it's some other kind of
+    /// file. The stream is a server half if the handle is the server end of
+    /// the pipe, and overlapped if the handle was opened for overlapped I/O.
+    /// On error the handle is left open and still owned by the caller.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be an open handle that is owned by nothing else.
+    pub unsafe fn try_from_raw_handle(handle: RawHandle) -> io::Result<PipeStream> {
+        let server_half = is_server_end(handle)?;
+        let overlapped = is_overlapped_handle(handle)?;
+        Ok(PipeStream::new(Handle { inner: handle }, server_half, PipeAccess::Duplex, overlapped))
+    }
+
+    /// Flushes the stream, disconnects it if it's the server half, and closes
+    /// it, returning the first error encountered. Dropping a `PipeStream`
+    /// does the same but ignores any errors.
+    pub fn close(self) -> io::Result<()> {
+        self.close_inner(true)
+    }
+
+    // What `close` does, without the flush unless `flush` is set.
+    fn close_inner(self, flush: bool) -> io::Result<()> {
+        let mut stream = std::mem::ManuallyDrop::new(self);
+        let released = stream.release(flush);
+        let ok = unsafe { CloseHandle(stream.handle.inner) };
+        let closed = if ok != 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        };
+        // the handle was closed above, any other fields that need dropping
+        // must be dropped here.
+        unsafe {
+            std::ptr::drop_in_place(&mut stream.shared);
+            std::ptr::drop_in_place(&mut stream.path);
+        }
+
+        released.and(closed)
+    }
+
+    // Flushes the stream if `flush` is set and, if this is the last handle to
+    // a server half, disconnects the client.
+    fn release(&self, flush: bool) -> io::Result<()> {
+        let disconnected = self.shared.disconnected.load(Ordering::Acquire);
+        let write_shutdown = self.shared.write_shutdown.load(Ordering::Acquire);
+        let mut result = Ok(());
+        if flush && self.access.writable(self.server_half) && !disconnected && !write_shutdown {
+            result = self.flush_inner();
+        }
+
+        let last_handle = self.shared.handles.fetch_sub(1, Ordering::AcqRel) == 1;
+        if self.server_half && last_handle && !disconnected {
+            let ok = unsafe { DisconnectNamedPipe(self.handle.inner) };
+            if ok == 0 && result.is_ok() {
+                result = Err(io::Error::last_os_error());
+            }
+        }
+
+        result
+    }
+
+    fn check_connected(&self) -> io::Result<()> {
+        if self.shared.disconnected.load(Ordering::Acquire) {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "the pipe was disconnected"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_server(&self) -> io::Result<()> {
+        if self.server_half {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                               "only valid on the server end of a pipe"))
+        }
+    }
+
+    fn peek_named_pipe(&self, buf: &mut [u8]) -> io::Result<(DWORD, DWORD, DWORD)> {
+        let mut bytes_read = 0;
+        let mut available = 0;
+        let mut left = 0;
+        let ok = unsafe {
+            PeekNamedPipe(self.handle.inner,
+                          buf.as_mut_ptr() as LPVOID,
+                          buf.len() as DWORD,
+                          &mut bytes_read,
+                          &mut available,
+                          &mut left)
+        };
+
+        if ok != 0 {
+            Ok((bytes_read, available, left))
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Wraps the stream in a `BufReader`. See also `BufferedPipeStream`,
+    /// which can still be written to.
+    pub fn into_buf_reader(self) -> BufReader<PipeStream> {
+        BufReader::new(self)
+    }
+
+    /// Wraps the stream in a `BufWriter`. Flushing the `BufWriter` also
+    /// flushes the stream, which waits for the other end to read everything
+    /// unless `set_lazy_flush` is on. `BufferedPipeWriter` only writes out
+    /// its buffer when flushed.
+    pub fn into_buf_writer(self) -> BufWriter<PipeStream> {
+        BufWriter::new(self)
+    }
+
+    /// Copies everything read from the stream to `dst` until the other end
+    /// closes or disconnects, returning the number of bytes copied. Like
+    /// `io::copy`, but with a buffer as large as the pipe's, so each read can
+    /// take everything the pipe holds.
+    pub fn copy_to<W: Write>(&mut self, dst: &mut W) -> io::Result<u64> {
+        let mut buf = self.copy_buffer();
+        let mut copied = 0;
+
+        loop {
+            let n = match self.read(&mut buf) {
+                Ok(0) => return Ok(copied),
+                Ok(n) => n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            dst.write_all(&buf[..n])?;
+            copied += n as u64;
+        }
+    }
+
+    // a buffer big enough to empty the pipe in one read.
+    fn copy_buffer(&self) -> Vec<u8> {
+        let size = self.info().map_or(65536, |info| {
+            std::cmp::max(info.in_buffer_size, info.out_buffer_size)
+        });
+        vec![0; std::cmp::max(size, 4096) as usize]
+    }
+
+    fn check_readable(&self) -> io::Result<()> {
+        if self.access.readable(self.server_half) {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                               "this end of the pipe is not readable"))
+        }
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.access.writable(self.server_half) {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                               "this end of the pipe is not writable"))
+        }
+    }
+
+    fn read_inner(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_message(buf).map(|(bytes_read, _)| bytes_read)
+    }
+
+    fn write_inner(&self, buf: &[u8]) -> io::Result<usize> {
+        self.write_with_timeout(buf, self.write_timeout()?)
+    }
+
+    fn write_with_timeout(&self, buf: &[u8], timeout: Option<Duration>) -> io::Result<usize> {
+        self.check_writable()?;
+        self.check_connected()?;
+        if self.shared.write_shutdown.load(Ordering::Acquire) {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                                      "the write half of the pipe was shut down"));
+        }
+        let mut written = 0;
+        let result = run_io(&self.handle, self.overlapped, timeout, &mut written, |bytes, ov| {
+            unsafe {
+                WriteFile(self.handle.inner,
+                          buf.as_ptr() as LPCVOID,
+                          buf.len() as DWORD,
+                          bytes,
+                          ov)
+            }
+        });
+
+        match result {
+            Ok(()) => {}
+            // report what was written before the failure, the error will come
+            // back from the next write.
+            Err(_) if written > 0 => {}
+            Err(err) => return Err(err),
+        }
+        self.shared.bytes_written.fetch_add(written as u64, Ordering::Relaxed);
+
+        if written == 0 && !buf.is_empty() && self.is_nonblocking() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "pipe buffer is full"));
+        }
+        Ok(written as usize)
+    }
+
+    fn read_vectored_inner(&self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        // ReadFile fills a single buffer, so read into one spanning every
+        // slice and copy out, letting a message fill several slices.
+        if bufs.iter().filter(|buf| !buf.is_empty()).count() <= 1 {
+            return match bufs.iter_mut().find(|buf| !buf.is_empty()) {
+                Some(buf) => self.read_inner(buf),
+                None => self.read_inner(&mut []),
+            };
+        }
+
+        let mut buf = vec![0; bufs.iter().map(|buf| buf.len()).sum()];
+        let n = self.read_inner(&mut buf)?;
+        let mut rest = &buf[..n];
+        for slice in bufs.iter_mut() {
+            if rest.is_empty() {
+                break;
+            }
+            let take = std::cmp::min(slice.len(), rest.len());
+            slice[..take].copy_from_slice(&rest[..take]);
+            rest = &rest[take..];
+        }
+        Ok(n)
+    }
+
+    // Reads until the end of the stream, sizing each read to the data
+    // already waiting in the pipe so a bulk transfer takes few large reads
+    // rather than many growing ones.
+    fn read_to_end_inner(&self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        const MIN_READ: usize = 8192;
+
+        let start = buf.len();
+        loop {
+            // a failed peek means the pipe is broken, which the read reports.
+            let available = self.peek_named_pipe(&mut []).map_or(0, |(_, available, _)| available);
+            let len = buf.len();
+            buf.resize(len + std::cmp::max(available as usize, MIN_READ), 0);
+            match self.read_inner(&mut buf[len..]) {
+                Ok(0) => {
+                    buf.truncate(len);
+                    return Ok(len - start);
+                }
+                Ok(n) => buf.truncate(len + n),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => buf.truncate(len),
+                Err(err) => {
+                    buf.truncate(len);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    fn write_vectored_inner(&self, bufs: &[IoSlice]) -> io::Result<usize> {
+        // WriteFile takes a single buffer, so the slices are copied into one
+        // to keep a single write, and so a single message, per call.
+        let mut nonempty = bufs.iter().filter(|buf| !buf.is_empty());
+        match (nonempty.next(), nonempty.next()) {
+            (None, _) => self.write_inner(&[]),
+            (Some(buf), None) => self.write_inner(buf),
+            _ => {
+                let mut buf = Vec::with_capacity(bufs.iter().map(|buf| buf.len()).sum());
+                for slice in bufs {
+                    buf.extend_from_slice(slice);
+                }
+                self.write_inner(&buf)
+            }
+        }
+    }
+
+    fn flush_inner(&self) -> io::Result<()> {
+        let ok = unsafe { FlushFileBuffers(self.handle.inner) };
+
+        if ok != 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+impl fmt::Debug for PipeStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PipeStream")
+            .field("server", &self.server_half)
+            .field("handle", &self.handle.inner)
+            .field("access", &self.access)
+            .field("overlapped", &self.overlapped)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl Drop for PipeStream {
+    fn drop(&mut self) {
+        // this runs before any field is dropped, so whatever order the fields
+        // are in, a server half is flushed and disconnected before `handle`
+        // closes it. `close` keeps the same order by hand.
+        let _ = self.release(true);
+    }
+}
+
+/// Reads return `Ok(0)` once the other end has gone away and everything it
+/// wrote has been read, whether it disconnected the pipe
+/// (`ERROR_PIPE_NOT_CONNECTED`) or closed its handle (`ERROR_BROKEN_PIPE`).
+/// Any other failure is returned as an error.
+///
+/// In message read mode a message larger than the buffer is returned over
+/// several short reads rather than as an `ERROR_MORE_DATA` error. Use
+/// `read_message` to find out where each message ends.
+///
+/// `read_vectored` makes one `ReadFile` into a buffer as large as all the
+/// slices together and copies the data out to them in order, so a single
+/// message can be received across several slices.
+///
+/// `read_to_end` peeks at how much data is waiting before each read and
+/// grows the buffer to fit it, so a bulk transfer takes few large reads.
+impl Read for PipeStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_inner(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.read_vectored_inner(bufs)
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.read_to_end_inner(buf)
+    }
+}
+
+/// `write_vectored` copies the slices into a single buffer and writes it
+/// with one `WriteFile`, so on a message pipe each call sends one message.
+impl Write for PipeStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_inner(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.write_vectored_inner(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_write()
+    }
+}
+
+/// Pipes can't seek, so this only exists for generic code that requires
+/// `Seek` without using it. `SeekFrom::Current(0)` reports a position of 0,
+/// and any other seek fails with `io::ErrorKind::Unsupported`.
+impl Seek for PipeStream {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) => Ok(0),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "pipes are not seekable")),
+        }
+    }
+}
+
+impl Read for &PipeStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_inner(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.read_vectored_inner(bufs)
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.read_to_end_inner(buf)
+    }
+}
+
+impl Write for &PipeStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_inner(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.write_vectored_inner(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_write()
+    }
+}
+
+/// An overlapped read or write in progress, started by
+/// `PipeStream::read_overlapped` or `PipeStream::write_overlapped`.
+///
+/// The operation owns its buffer until it completes. Dropping a `PendingIo`
+/// that hasn't completed cancels the operation and waits for it to stop.
+#[derive(Debug)]
+pub struct PendingIo<'a> {
+    handle: &'a Handle,
+    // boxed so the OVERLAPPED and buffer the kernel writes to don't move.
+    state: Box<IoState>,
+}
+
+#[derive(Debug)]
+struct IoState {
+    overlapped: OVERLAPPED,
+    event: Handle,
+    buf: Vec<u8>,
+}
+
+impl<'a> PendingIo<'a> {
+    fn start<F>(handle: &'a Handle, buf: Vec<u8>, f: F) -> io::Result<PendingIo<'a>>
+        where F: FnOnce(&mut [u8], LPOVERLAPPED) -> BOOL
+    {
+        let event = create_event()?;
+        let mut state = Box::new(IoState {
+            overlapped: new_overlapped(&event),
+            event,
+            buf,
+        });
+
+        let state_ref = &mut *state;
+        let ok = f(&mut state_ref.buf, &mut state_ref.overlapped);
+        if ok == 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error().map(|x| x as u32) {
+                Some(ERROR_IO_PENDING) | Some(ERROR_MORE_DATA) => {}
+                _ => return Err(err),
+            }
+        }
+
+        Ok(PendingIo { handle, state })
+    }
+
+    fn result(&mut self, wait: bool) -> io::Result<Option<usize>> {
+        let mut bytes = 0;
+        let ok = unsafe {
+            GetOverlappedResult(self.handle.inner,
+                                &mut self.state.overlapped,
+                                &mut bytes,
+                                wait as BOOL)
+        };
+
+        if ok != 0 {
+            return Ok(Some(bytes as usize));
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error().map(|x| x as u32) {
+            Some(ERROR_IO_INCOMPLETE) => Ok(None),
+            // the rest of the message is left for the next read.
+            Some(ERROR_MORE_DATA) => Ok(Some(bytes as usize)),
+            _ => Err(err),
+        }
+    }
+
+    // The address of the OVERLAPPED, which identifies the operation to
+    // `PipeStream::cancel_overlapped`. It stays put until the `PendingIo` is
+    // dropped.
+    #[cfg(feature = "tokio")]
+    fn overlapped_addr(&self) -> usize {
+        &self.state.overlapped as *const OVERLAPPED as usize
+    }
+
+    fn is_pending(&self) -> bool {
+        // the kernel updates Internal when the operation completes, like
+        // HasOverlappedIoCompleted.
+        let status = unsafe { std::ptr::read_volatile(&self.state.overlapped.Internal) };
+        status == STATUS_PENDING as ULONG_PTR
+    }
+
+    /// Checks whether the operation has completed without blocking, returning
+    /// the number of bytes transferred if it has.
+    pub fn poll(&mut self) -> io::Result<Option<usize>> {
+        self.result(false)
+    }
+
+    /// Blocks until the operation completes, returning the number of bytes
+    /// transferred.
+    pub fn wait(&mut self) -> io::Result<usize> {
+        self.result(true).map(|bytes| bytes.unwrap_or(0))
+    }
+
+    /// Blocks until the operation completes or `timeout` elapses, returning
+    /// `Ok(None)` on timeout. The operation is left running on timeout.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> io::Result<Option<usize>> {
+        let ms = duration_to_ms(timeout);
+        match unsafe { WaitForSingleObject(self.state.event.inner, ms) } {
+            WAIT_OBJECT_0 => self.result(false),
+            WAIT_TIMEOUT => Ok(None),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Cancels the operation if it's still running and waits for it to stop,
+    /// returning the number of bytes transferred before it stopped.
+    pub fn cancel(&mut self) -> io::Result<usize> {
+        if self.is_pending() {
+            let _ = unsafe { CancelIoEx(self.handle.inner, &mut self.state.overlapped) };
+        }
+        match self.wait() {
+            Err(ref err) if err.raw_os_error() == Some(ERROR_OPERATION_ABORTED as i32) => {
+                Ok(self.state.overlapped.InternalHigh as usize)
+            }
+            result => result,
+        }
+    }
+
+    /// The event signaled when the operation completes, for use with
+    /// `WaitForMultipleObjects` and friends. It is closed when the
+    /// `PendingIo` is dropped.
+    pub fn event(&self) -> RawHandle {
+        self.state.event.inner
+    }
+
+    /// Cancels the operation if it's still running and returns its buffer.
+    /// For reads, the data read is at the start of the buffer.
+    pub fn into_buffer(mut self) -> Vec<u8> {
+        let _ = self.cancel();
+        std::mem::take(&mut self.state.buf)
+    }
+}
+
+impl<'a> Drop for PendingIo<'a> {
+    fn drop(&mut self) {
+        if self.is_pending() {
+            let _ = self.cancel();
+        }
+    }
+}
+
+/// An event tied to a stream's readiness to read, created by
+/// `PipeStream::readiness_event`.
+///
+/// A zero byte overlapped read signals the event once there is data, without
+/// consuming any. The event stays signaled until `rearm` is called after
+/// reading, which starts another zero byte read. The raw handle stays the
+/// same for the life of the `ReadinessEvent`, which borrows the stream so it
+/// can't outlive it. Dropping it cancels the read and closes the event, so
+/// it must not be waited on after that.
+#[derive(Debug)]
+pub struct ReadinessEvent<'a> {
+    handle: &'a Handle,
+    // boxed so the OVERLAPPED the kernel writes to doesn't move.
+    state: Box<IoState>,
+    pending: bool,
+}
+
+impl<'a> ReadinessEvent<'a> {
+    fn arm(&mut self) -> io::Result<()> {
+        self.state.overlapped = new_overlapped(&self.state.event);
+        let state = &mut *self.state;
+        let ok = unsafe {
+            ReadFile(self.handle.inner,
+                     state.buf.as_mut_ptr() as LPVOID,
+                     0,
+                     std::ptr::null_mut(),
+                     &mut state.overlapped)
+        };
+        if ok == 0 && unsafe { GetLastError() } == ERROR_IO_PENDING {
+            self.pending = true;
+            return Ok(());
+        }
+
+        // data, or an error such as the pipe being broken, straight away.
+        self.pending = false;
+        if unsafe { SetEvent(self.state.event.inner) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn is_pending(&self) -> bool {
+        let status = unsafe { std::ptr::read_volatile(&self.state.overlapped.Internal) };
+        self.pending && status == STATUS_PENDING as ULONG_PTR
+    }
+
+    /// Whether the event is signaled, without waiting.
+    pub fn is_ready(&self) -> bool {
+        !self.is_pending()
+    }
+
+    /// Starts waiting for more data after the last of it has been read. Does
+    /// nothing if the stream isn't ready yet; if data is still left the
+    /// event is signaled again straight away.
+    pub fn rearm(&mut self) -> io::Result<()> {
+        if self.is_pending() {
+            return Ok(());
+        }
+        self.arm()
+    }
+}
+
+impl<'a> AsRawHandle for ReadinessEvent<'a> {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.state.event.inner
+    }
+}
+
+impl<'a> Drop for ReadinessEvent<'a> {
+    fn drop(&mut self) {
+        if self.is_pending() {
+            let mut bytes = 0;
+            let overlapped = &mut self.state.overlapped;
+            unsafe {
+                CancelIoEx(self.handle.inner, overlapped);
+                GetOverlappedResult(self.handle.inner, overlapped, &mut bytes, TRUE);
+            }
+        }
+    }
+}
+
+fn create_event() -> io::Result<Handle> {
+    let event = unsafe { CreateEventW(std::ptr::null_mut(), TRUE, FALSE, std::ptr::null()) };
+
+    if !event.is_null() {
+        Ok(Handle { inner: event })
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// An OVERLAPPED that signals `event` when the operation completes. The low
+// bit of the event is set so the completion isn't also queued to a port the
+// handle was registered with by `register_iocp`; wait functions ignore it.
+fn new_overlapped(event: &Handle) -> OVERLAPPED {
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    overlapped.hEvent = (event.inner as usize | 1) as HANDLE;
+    overlapped
+}
+
+// Runs a blocking I/O call on `handle`, waiting for it to complete if the
+// handle was opened for overlapped I/O. `f` gets the pointers to pass as the
+// bytes transferred and OVERLAPPED arguments. `timeout` is only supported for
+// overlapped handles, and the call is cancelled if it expires.
+fn run_io<F>(handle: &Handle,
+             overlapped: bool,
+             timeout: Option<Duration>,
+             bytes: &mut DWORD,
+             f: F)
+             -> io::Result<()>
+    where F: FnOnce(LPDWORD, LPOVERLAPPED) -> BOOL
+{
+    if !overlapped {
+        let ok = f(bytes, std::ptr::null_mut());
+        return if ok != 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        };
+    }
+
+    let event = create_event()?;
+    let mut overlapped = new_overlapped(&event);
+
+    let ok = f(std::ptr::null_mut(), &mut overlapped);
+    if ok == 0 {
+        let err = io::Error::last_os_error();
+        match err.raw_os_error().map(|x| x as u32) {
+            Some(ERROR_IO_PENDING) | Some(ERROR_MORE_DATA) => {}
+            _ => return Err(err),
+        }
+    }
+
+    let mut timed_out = false;
+    if let Some(timeout) = timeout {
+        if unsafe { WaitForSingleObject(event.inner, duration_to_ms(timeout)) } == WAIT_TIMEOUT {
+            let _ = unsafe { CancelIoEx(handle.inner, &mut overlapped) };
+            timed_out = true;
+        }
+    }
+
+    let ok = unsafe { GetOverlappedResult(handle.inner, &mut overlapped, bytes, TRUE) };
+    if ok != 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error().map(|x| x as u32) {
+        // report whatever was transferred before the timeout.
+        Some(ERROR_OPERATION_ABORTED) if timed_out && *bytes > 0 => Ok(()),
+        Some(ERROR_OPERATION_ABORTED) if timed_out => {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "pipe operation timed out"))
+        }
+        _ => Err(err),
+    }
+}
+
+/// The configuration of a pipe, as returned by `PipeStream::info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipeInfo {
+    /// The raw `PIPE_*` flags reported by `GetNamedPipeInfo`.
+    pub flags: u32,
+    /// The size of the output buffer in bytes.
+    pub out_buffer_size: u32,
+    /// The size of the input buffer in bytes.
+    pub in_buffer_size: u32,
+    /// The maximum number of instances, 255 meaning unlimited.
+    pub max_instances: u32,
+}
+
+/// The state of one end of a pipe, as returned by `PipeStream::handle_state`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandleState {
+    /// Whether the handle is in `PIPE_NOWAIT` mode.
+    pub nonblocking: bool,
+    /// How reads from this end are delimited.
+    pub read_mode: PipeMode,
+    /// How many instances of the pipe currently exist.
+    pub current_instances: u32,
+    /// The most bytes a remote client collects before sending them.
+    pub max_collection_count: Option<u32>,
+    /// The longest a remote client waits before sending collected bytes.
+    pub collect_data_timeout: Option<Duration>,
+    /// The user name of the client, as seen by the server.
+    pub user_name: Option<OsString>,
+}
+
+/// Byte counts for a stream, as returned by `PipeStream::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PipeStats {
+    /// The total number of bytes read.
+    pub bytes_read: u64,
+    /// The total number of bytes written.
+    pub bytes_written: u64,
+}
+
+impl PipeInfo {
+    /// Whether the handle is the server end of the pipe.
+    pub fn is_server_end(&self) -> bool {
+        self.flags & PIPE_SERVER_END != 0
+    }
+
+    /// Whether the pipe is a message-type pipe.
+    pub fn is_message_type(&self) -> bool {
+        self.flags & PIPE_TYPE_MESSAGE != 0
+    }
+
+    /// The type the pipe was created with.
+    pub fn mode(&self) -> PipeMode {
+        if self.is_message_type() {
+            PipeMode::Message
+        } else {
+            PipeMode::Byte
+        }
+    }
+}
+
+/// A `PipeStream` with a read buffer, so it implements `BufRead` while
+/// writes still go straight to the pipe.
+///
+/// With message-mode pipes each `fill_buf` returns data from at most one
+/// message, so message boundaries survive buffering.
+#[derive(Debug)]
+pub struct BufferedPipeStream {
+    inner: BufReader<PipeStream>,
+}
+
+impl BufferedPipeStream {
+    pub fn new(stream: PipeStream) -> BufferedPipeStream {
+        BufferedPipeStream { inner: BufReader::new(stream) }
+    }
+
+    pub fn with_capacity(capacity: usize, stream: PipeStream) -> BufferedPipeStream {
+        BufferedPipeStream { inner: BufReader::with_capacity(capacity, stream) }
+    }
+
+    pub fn get_ref(&self) -> &PipeStream {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut PipeStream {
+        self.inner.get_mut()
+    }
+
+    /// Returns the underlying stream. Any buffered data that hasn't been read
+    /// yet is lost.
+    pub fn into_inner(self) -> PipeStream {
+        self.inner.into_inner()
+    }
+}
+
+impl Read for BufferedPipeStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl BufRead for BufferedPipeStream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+impl Write for BufferedPipeStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.get_mut().flush()
+    }
+}
+
+/// A `PipeStream` with a write buffer, whose `flush` only writes the buffered
+/// data to the pipe without waiting for the other end to read it. `sync`
+/// does both. Buffered data is written out when the writer is dropped.
+///
+/// Writes are coalesced, so on message pipes a message can end up split or
+/// merged with others; use it with byte pipes.
+#[derive(Debug)]
+pub struct BufferedPipeWriter {
+    stream: PipeStream,
+    buf: Vec<u8>,
+}
+
+impl BufferedPipeWriter {
+    pub fn new(stream: PipeStream) -> BufferedPipeWriter {
+        BufferedPipeWriter::with_capacity(8192, stream)
+    }
+
+    pub fn with_capacity(capacity: usize, stream: PipeStream) -> BufferedPipeWriter {
+        BufferedPipeWriter {
+            stream,
+            buf: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn get_ref(&self) -> &PipeStream {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut PipeStream {
+        &mut self.stream
+    }
+
+    /// The data written but not yet sent to the pipe.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Writes out the buffer, then waits for the other end to read
+    /// everything written to the pipe, like `PipeStream::sync_data`.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.flush_buf()?;
+        self.stream.sync_data()
+    }
+
+    /// Writes out the buffer and returns the underlying stream.
+    pub fn into_inner(mut self) -> io::Result<PipeStream> {
+        self.flush_buf()?;
+        let mut writer = std::mem::ManuallyDrop::new(self);
+        unsafe {
+            std::ptr::drop_in_place(&mut writer.buf);
+            Ok(std::ptr::read(&writer.stream))
+        }
+    }
+
+    fn flush_buf(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        let result = loop {
+            if written == self.buf.len() {
+                break Ok(());
+            }
+            match self.stream.write_inner(&self.buf[written..]) {
+                Ok(0) => {
+                    break Err(io::Error::new(io::ErrorKind::WriteZero,
+                                             "failed to write the buffered data"))
+                }
+                Ok(n) => written += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => break Err(err),
+            }
+        };
+        // keep whatever wasn't written for the next flush.
+        self.buf.drain(..written);
+        result
+    }
+}
+
+impl Write for BufferedPipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + buf.len() > self.buf.capacity() {
+            self.flush_buf()?;
+        }
+        if buf.len() >= self.buf.capacity() {
+            self.stream.write_inner(buf)
+        } else {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()
+    }
+}
+
+impl Drop for BufferedPipeWriter {
+    fn drop(&mut self) {
+        let _ = self.flush_buf();
+    }
+}
+
+/// How `FramedPipe` encodes the length written before each frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LengthPrefix {
+    /// A little-endian `u16`.
+    U16Le,
+    /// A big-endian `u16`.
+    U16Be,
+    /// A little-endian `u32`.
+    #[default]
+    U32Le,
+    /// A big-endian `u32`.
+    U32Be,
+}
+
+impl LengthPrefix {
+    fn size(self) -> usize {
+        match self {
+            LengthPrefix::U16Le | LengthPrefix::U16Be => 2,
+            LengthPrefix::U32Le | LengthPrefix::U32Be => 4,
+        }
+    }
+
+    fn encode(self, len: usize, out: &mut Vec<u8>) -> io::Result<()> {
+        let max = match self {
+            LengthPrefix::U16Le | LengthPrefix::U16Be => u16::MAX as usize,
+            LengthPrefix::U32Le | LengthPrefix::U32Be => u32::MAX as usize,
+        };
+        if len > max {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame is too long"));
+        }
+
+        match self {
+            LengthPrefix::U16Le => out.extend_from_slice(&(len as u16).to_le_bytes()),
+            LengthPrefix::U16Be => out.extend_from_slice(&(len as u16).to_be_bytes()),
+            LengthPrefix::U32Le => out.extend_from_slice(&(len as u32).to_le_bytes()),
+            LengthPrefix::U32Be => out.extend_from_slice(&(len as u32).to_be_bytes()),
+        }
+        Ok(())
+    }
+
+    fn decode(self, bytes: &[u8]) -> u64 {
+        match self {
+            LengthPrefix::U16Le => u16::from_le_bytes([bytes[0], bytes[1]]) as u64,
+            LengthPrefix::U16Be => u16::from_be_bytes([bytes[0], bytes[1]]) as u64,
+            LengthPrefix::U32Le => {
+                u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64
+            }
+            LengthPrefix::U32Be => {
+                u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64
+            }
+        }
+    }
+}
+
+/// A byte pipe carrying length-prefixed frames, for keeping message
+/// boundaries without a message-type pipe.
+#[derive(Debug)]
+pub struct FramedPipe {
+    stream: PipeStream,
+    prefix: LengthPrefix,
+    max_frame_size: usize,
+}
+
+impl FramedPipe {
+    /// Wraps `stream`, prefixing frames with a little-endian `u32` length.
+    /// Frames of up to 16 MiB are accepted.
+    pub fn new(stream: PipeStream) -> FramedPipe {
+        FramedPipe::with_length_prefix(stream, LengthPrefix::default())
+    }
+
+    pub fn with_length_prefix(stream: PipeStream, prefix: LengthPrefix) -> FramedPipe {
+        FramedPipe {
+            stream,
+            prefix,
+            max_frame_size: 16 * 1024 * 1024,
+        }
+    }
+
+    /// The longest frame `recv_frame` accepts.
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Sets the longest frame `recv_frame` accepts, so a peer can't make it
+    /// allocate an arbitrary amount of memory.
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    pub fn get_ref(&self) -> &PipeStream {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut PipeStream {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> PipeStream {
+        self.stream
+    }
+
+    /// Writes `frame` preceded by its length. Fails with
+    /// `io::ErrorKind::InvalidInput` if the length doesn't fit the prefix.
+    pub fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(self.prefix.size() + frame.len());
+        self.prefix.encode(frame.len(), &mut buf)?;
+        buf.extend_from_slice(frame);
+        self.stream.write_all(&buf)
+    }
+
+    /// Reads the next frame, however many reads it arrives in. Fails with
+    /// `io::ErrorKind::UnexpectedEof` if the pipe closes part way through,
+    /// and with `io::ErrorKind::InvalidData` if the frame is longer than
+    /// `max_frame_size`, before reading anything past its length.
+    pub fn recv_frame(&mut self) -> io::Result<Vec<u8>> {
+        let mut len = [0; 4];
+        let len = &mut len[..self.prefix.size()];
+        self.stream.read_exact(len)?;
+        let len = self.prefix.decode(len);
+        if len > self.max_frame_size as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "frame is longer than the maximum frame size"));
+        }
+
+        let mut frame = vec![0; len as usize];
+        self.stream.read_exact(&mut frame)?;
+        Ok(frame)
+    }
+}
+
+/// The reading half of a `PipeStream`, created by `PipeStream::split`.
+#[derive(Debug)]
+pub struct ReadHalf {
+    stream: Arc<PipeStream>,
+}
+
+/// The writing half of a `PipeStream`, created by `PipeStream::split`.
+#[derive(Debug)]
+pub struct WriteHalf {
+    stream: Arc<PipeStream>,
+}
+
+impl ReadHalf {
+    /// Recombines the two halves of a split stream. Fails, returning both
+    /// halves, if they were not split from the same stream.
+    pub fn reunite(self, write: WriteHalf) -> Result<PipeStream, ReuniteError> {
+        if !Arc::ptr_eq(&self.stream, &write.stream) {
+            return Err(ReuniteError(self, write));
+        }
+
+        drop(write);
+        match Arc::try_unwrap(self.stream) {
+            Ok(stream) => Ok(stream),
+            Err(_) => unreachable!("both halves of a split stream were reunited"),
+        }
+    }
+}
+
+impl Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read_inner(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.stream.read_vectored_inner(bufs)
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.stream.read_to_end_inner(buf)
+    }
+}
+
+impl Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write_inner(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.stream.write_vectored_inner(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush_write()
+    }
+}
+
+/// A `PipeStream` shared by every clone, created by
+/// `PipeStream::into_shared`. Reading or writing through any clone uses the
+/// same pipe, and the pipe is closed when the last clone is dropped.
+#[derive(Debug, Clone)]
+pub struct SharedPipeStream {
+    stream: Arc<PipeStream>,
+}
+
+impl SharedPipeStream {
+    pub fn get_ref(&self) -> &PipeStream {
+        &self.stream
+    }
+
+    /// Returns the stream if this is the last clone.
+    pub fn try_unwrap(self) -> Result<PipeStream, SharedPipeStream> {
+        Arc::try_unwrap(self.stream).map_err(|stream| SharedPipeStream { stream })
+    }
+}
+
+impl From<PipeStream> for SharedPipeStream {
+    fn from(stream: PipeStream) -> SharedPipeStream {
+        stream.into_shared()
+    }
+}
+
+impl Read for SharedPipeStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read_inner(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.stream.read_vectored_inner(bufs)
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        self.stream.read_to_end_inner(buf)
+    }
+}
+
+impl Write for SharedPipeStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write_inner(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.stream.write_vectored_inner(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush_write()
+    }
+}
+
+/// Reverts the calling thread to its own security context when dropped,
+/// ending an impersonation started by `PipeStream::impersonate_client`.
+///
+/// If reverting fails the process is aborted, since carrying on with the
+/// client's privileges is never safe.
+#[derive(Debug)]
+pub struct ImpersonationGuard {
+    // impersonation belongs to a thread, so the guard must stay on it.
+    _thread: PhantomData<*const ()>,
+}
+
+impl Drop for ImpersonationGuard {
+    fn drop(&mut self) {
+        if unsafe { RevertToSelf() } == 0 {
+            std::process::abort();
+        }
+    }
+}
+
+/// The error returned when reuniting halves that came from different streams.
+#[derive(Debug)]
+pub struct ReuniteError(pub ReadHalf, pub WriteHalf);
+
+impl fmt::Display for ReuniteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("tried to reunite halves that are not from the same stream")
+    }
+}
+
+impl Error for ReuniteError {}
+
+/// The error returned when converting an `OwnedHandle` that isn't a pipe
+/// into a `PipeStream`, holding the handle and why it was rejected.
+#[derive(Debug)]
+pub struct TryFromHandleError(pub OwnedHandle, pub io::Error);
+
+impl fmt::Display for TryFromHandleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "handle can't be used as a pipe: {}", self.1)
+    }
+}
+
+impl Error for TryFromHandleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.1)
+    }
+}
+
+/// The common ways a pipe operation fails, for matching without comparing
+/// raw OS error codes. Convert an `io::Error` from any method with
+/// `PipeError::from`, and back again with `io::Error::from`.
+///
+/// ```no_run
+/// use windows_named_pipe::{PipeError, PipeStream};
+///
+/// match PipeStream::connect(r"\\.\pipe\example").map_err(PipeError::from) {
+///     Ok(stream) => drop(stream),
+///     Err(PipeError::NotFound) => println!("the server isn't running"),
+///     Err(PipeError::Busy) => println!("every instance is busy"),
+///     Err(err) => println!("failed to connect: {}", err),
+/// }
+/// ```
+#[derive(Debug)]
+pub enum PipeError {
+    /// Every instance of the pipe is busy, `ERROR_PIPE_BUSY`.
+    Busy,
+    /// There is no pipe with that name, `ERROR_FILE_NOT_FOUND`.
+    NotFound,
+    /// The other end closed the pipe, `ERROR_BROKEN_PIPE` or `ERROR_NO_DATA`,
+    /// or the write half was shut down.
+    BrokenPipe,
+    /// The pipe isn't connected, `ERROR_PIPE_NOT_CONNECTED`.
+    NotConnected,
+    /// A message was larger than the buffer it was read into,
+    /// `ERROR_MORE_DATA`.
+    MoreData,
+    /// The operation didn't finish in time.
+    Timeout,
+    /// Any other error.
+    Other(io::Error),
+}
+
+impl From<io::Error> for PipeError {
+    fn from(err: io::Error) -> PipeError {
+        match err.raw_os_error().map(|x| x as u32) {
+            Some(ERROR_PIPE_BUSY) => return PipeError::Busy,
+            Some(ERROR_FILE_NOT_FOUND) => return PipeError::NotFound,
+            Some(ERROR_BROKEN_PIPE) |
+            Some(ERROR_NO_DATA) => return PipeError::BrokenPipe,
+            Some(ERROR_PIPE_NOT_CONNECTED) => return PipeError::NotConnected,
+            Some(ERROR_MORE_DATA) => return PipeError::MoreData,
+            Some(ERROR_SEM_TIMEOUT) => return PipeError::Timeout,
+            Some(_) => return PipeError::Other(err),
+            None => {}
+        }
+
+        // the errors this crate makes itself.
+        match err.kind() {
+            io::ErrorKind::BrokenPipe => PipeError::BrokenPipe,
+            io::ErrorKind::NotConnected => PipeError::NotConnected,
+            io::ErrorKind::TimedOut => PipeError::Timeout,
+            _ => PipeError::Other(err),
+        }
+    }
+}
+
+impl From<PipeError> for io::Error {
+    fn from(err: PipeError) -> io::Error {
+        match err {
+            PipeError::Busy => io::Error::from_raw_os_error(ERROR_PIPE_BUSY as i32),
+            PipeError::NotFound => io::Error::from_raw_os_error(ERROR_FILE_NOT_FOUND as i32),
+            PipeError::BrokenPipe => io::Error::from_raw_os_error(ERROR_BROKEN_PIPE as i32),
+            PipeError::NotConnected => {
+                io::Error::from_raw_os_error(ERROR_PIPE_NOT_CONNECTED as i32)
+            }
+            PipeError::MoreData => io::Error::from_raw_os_error(ERROR_MORE_DATA as i32),
+            PipeError::Timeout => io::Error::new(io::ErrorKind::TimedOut, PipeError::Timeout),
+            PipeError::Other(err) => err,
+        }
+    }
+}
+
+impl fmt::Display for PipeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PipeError::Busy => f.write_str("all pipe instances are busy"),
+            PipeError::NotFound => f.write_str("the pipe does not exist"),
+            PipeError::BrokenPipe => f.write_str("the pipe has been ended"),
+            PipeError::NotConnected => f.write_str("the pipe is not connected"),
+            PipeError::MoreData => f.write_str("more data is available"),
+            PipeError::Timeout => f.write_str("the pipe operation timed out"),
+            PipeError::Other(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for PipeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            PipeError::Other(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl AsRawHandle for PipeStream {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.handle.inner
+    }
+}
+
+impl AsHandle for PipeStream {
+    fn as_handle<'a>(&'a self) -> BorrowedHandle<'a> {
+        unsafe { BorrowedHandle::borrow_raw(self.handle.inner) }
+    }
+}
+
+impl From<PipeStream> for OwnedHandle {
+    fn from(stream: PipeStream) -> OwnedHandle {
+        unsafe { OwnedHandle::from_raw_handle(stream.into_raw_handle()) }
+    }
+}
+
+impl IntoRawHandle for PipeStream {
+    fn into_raw_handle(self) -> RawHandle {
+        let mut stream = std::mem::ManuallyDrop::new(self);
+        // the handle is no longer one of ours, so clones don't wait for it.
+        stream.shared.handles.fetch_sub(1, Ordering::AcqRel);
+        let handle = stream.handle.inner;
+        unsafe {
+            std::ptr::drop_in_place(&mut stream.shared);
+            std::ptr::drop_in_place(&mut stream.path);
+        }
+        handle
+    }
+}
+
+/// Lets the stream be the stdin, stdout or stderr of a child process. The
+/// stream should not have been opened for overlapped I/O, since the child
+/// will use it synchronously.
+impl From<PipeStream> for std::process::Stdio {
+    fn from(stream: PipeStream) -> std::process::Stdio {
+        unsafe { std::process::Stdio::from_raw_handle(stream.into_raw_handle()) }
+    }
+}
+
+/// Takes ownership of `handle` if it refers to a pipe, otherwise hands it
+/// back in the error. The stream is a server half if the handle is the
+/// server end of the pipe, and overlapped if the handle was opened for
+/// overlapped I/O.
+impl TryFrom<OwnedHandle> for PipeStream {
+    type Error = TryFromHandleError;
+
+    fn try_from(handle: OwnedHandle) -> Result<PipeStream, TryFromHandleError> {
+        let raw = handle.as_raw_handle();
+        match is_server_end(raw).and_then(|server| Ok((server, is_overlapped_handle(raw)?))) {
+            Ok((server_half, overlapped)) => {
+                let handle = Handle { inner: handle.into_raw_handle() };
+                Ok(PipeStream::new(handle, server_half, PipeAccess::Duplex, overlapped))
+            }
+            Err(err) => Err(TryFromHandleError(handle, err)),
+        }
+    }
+}
+
+// Whether `handle` was opened for overlapped I/O, which is the case unless
+// the kernel does its I/O synchronously.
+fn is_overlapped_handle(handle: RawHandle) -> io::Result<bool> {
+    let mode = file_mode(handle)?;
+    Ok(mode & (FILE_SYNCHRONOUS_IO_ALERT | FILE_SYNCHRONOUS_IO_NONALERT) == 0)
+}
+
+// The FILE_* mode flags `handle` was opened with, from FileModeInformation.
+fn file_mode(handle: RawHandle) -> io::Result<ULONG> {
+    let mut status = IoStatusBlock { status: 0, information: 0 };
+    let mut mode: ULONG = 0;
+    let ret = unsafe {
+        NtQueryInformationFile(handle,
+                               &mut status,
+                               &mut mode as *mut ULONG as LPVOID,
+                               std::mem::size_of::<ULONG>() as ULONG,
+                               FILE_MODE_INFORMATION)
+    };
+    if ret < 0 {
+        let code = unsafe { RtlNtStatusToDosError(ret) };
+        return Err(io::Error::from_raw_os_error(code as i32));
+    }
+
+    Ok(mode)
+}
+
+// Checks that `handle` is a pipe, and returns whether it's the server end.
+fn is_server_end(handle: RawHandle) -> io::Result<bool> {
+    match unsafe { GetFileType(handle) } {
+        FILE_TYPE_PIPE => {}
+        FILE_TYPE_UNKNOWN => {
+            // an unknown type is only an error if the call set one.
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(ERROR_SUCCESS as i32) {
+                return Err(err);
+            }
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "handle is not a pipe"));
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "handle is not a pipe")),
+    }
+
+    let mut flags = 0;
+    let ok = unsafe {
+        GetNamedPipeInfo(handle,
+                         &mut flags,
+                         std::ptr::null_mut(),
+                         std::ptr::null_mut(),
+                         std::ptr::null_mut())
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(flags & PIPE_SERVER_END != 0)
+}
+
+impl FromRawHandle for PipeStream {
+    unsafe fn from_raw_handle(handle: RawHandle) -> Self {
+        PipeStream::new(Handle { inner: handle }, false, PipeAccess::Duplex, false)
+    }
+}
+
+#[derive(Debug)]
+pub struct PipeListener {
+    path: Vec<u16>,
+    // the path as given to bind, for the streams accepted.
+    name: Arc<OsStr>,
+    // instances waiting for clients, oldest first. Fewer than the backlog
+    // when max_instances stopped more being created.
+    spares: VecDeque<Handle>,
+    // an instance connected by wait_connect that accept hasn't returned yet.
+    connected: Option<Handle>,
+    options: PipeListenerBuilder,
+    shutdown: Arc<ShutdownState>,
+}
+
+/// Stops a `PipeListener` from another thread, returned by
+/// `PipeListener::shutdown_handle`.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+#[derive(Debug)]
+struct ShutdownState {
+    shutdown: AtomicBool,
+    // the instance an accept is waiting on, as a usize so it can be shared.
+    waiting: Mutex<Option<usize>>,
+}
+
+impl ShutdownHandle {
+    /// Shuts the listener down. An accept waiting for a client is woken, and
+    /// it and every later accept fail with `ErrorKind::ConnectionAborted`.
+    /// A client that connected before the wait was cancelled is still
+    /// returned. Returns once any waiting accept has been woken.
+    pub fn signal(&self) {
+        self.state.shutdown.store(true, Ordering::Release);
+        // the accept may not have started waiting when it is first
+        // cancelled, so keep at it until the accept notices.
+        loop {
+            match *self.state.waiting.lock().unwrap() {
+                Some(handle) => unsafe {
+                    CancelIoEx(handle as HANDLE, std::ptr::null_mut());
+                },
+                None => return,
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Whether `signal` has been called.
+    pub fn is_signaled(&self) -> bool {
+        self.state.shutdown.load(Ordering::Acquire)
+    }
+}
+
+/// A validated pipe path, for passing to `PipeListener::bind` or
+/// `PipeStream::connect`.
+///
+/// Pipe names may not be empty or contain path separators or NULs, and the
+/// whole path is limited to 256 characters. The `Path` taken by `bind`,
+/// `connect` and the like is checked with `PipeName::from_path`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PipeName {
+    path: String,
+}
+
+impl PipeName {
+    /// The pipe `name` on this computer, `\\.\pipe\name`.
+    pub fn local(name: &str) -> io::Result<PipeName> {
+        PipeName::remote(".", name)
+    }
+
+    /// Like `local`, for names that may come with a `Global\` prefix. Unlike
+    /// events or mutexes, pipes are never private to a session, so the
+    /// prefix has no meaning and is removed rather than becoming part of the
+    /// name.
+    pub fn global(name: &str) -> io::Result<PipeName> {
+        let prefixed = name.get(..7).is_some_and(|prefix| prefix.eq_ignore_ascii_case("Global\\"));
+        let name = if prefixed { &name[7..] } else { name };
+        PipeName::local(name)
+    }
+
+    /// The pipe `name` on the computer named `server`,
+    /// `\\server\pipe\name`.
+    pub fn remote(server: &str, name: &str) -> io::Result<PipeName> {
+        fn check(name: &str) -> io::Result<()> {
+            if name.is_empty() || name.contains(&['\\', '/', '\0'][..]) {
+                Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "server and pipe names cannot be empty or contain \
+                                    separators or NULs"))
+            } else {
+                Ok(())
+            }
+        }
+        check(server)?;
+        check(name)?;
+
+        PipeName::new(format!(r"\\{}\pipe\{}", server, name))
+    }
+
+    /// Checks a full pipe path such as `\\.\pipe\name`, where `/` may be
+    /// used in place of `\`. Unlike the other constructors the name may
+    /// contain separators, as in `\\.\pipe\LOCAL\name`, which some
+    /// software uses.
+    pub fn from_path(path: &Path) -> io::Result<PipeName> {
+        let invalid = || {
+            io::Error::new(io::ErrorKind::InvalidInput,
+                           "pipe paths must look like \\\\server\\pipe\\name")
+        };
+        let path = path.to_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                                          "pipe paths must be valid Unicode"))?;
+        if path.contains('\0') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "pipe paths cannot contain NULs"));
+        }
+        // Windows treats both separators the same in these paths.
+        let path = path.replace('/', "\\");
+
+        let rest = path.strip_prefix(r"\\").ok_or_else(invalid)?;
+        let (server, rest) = rest.split_once('\\').ok_or_else(invalid)?;
+        let (pipe, name) = rest.split_once('\\').ok_or_else(invalid)?;
+        if server.is_empty() || !pipe.eq_ignore_ascii_case("pipe") || name.is_empty() {
+            return Err(invalid());
+        }
+        PipeName::new(path)
+    }
+
+    fn new(path: String) -> io::Result<PipeName> {
+        if path.encode_utf16().count() > 256 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "pipe paths are limited to 256 characters"));
+        }
+        Ok(PipeName { path })
+    }
+
+    // The path as a NUL-terminated wide string for the API calls.
+    fn to_wide(&self) -> io::Result<Vec<u16>> {
+        to_u16s(&self.path)
+    }
+
+    /// The full path of the pipe.
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for PipeName {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.path)
+    }
+}
+
+impl fmt::Display for PipeName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+/// How data written to a pipe is delimited on the reading side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeMode {
+    /// Data is a stream of bytes, like a socket.
+    Byte,
+    /// Each write is read back as a separate message.
+    Message,
+}
+
+impl PipeMode {
+    fn pipe_type(self) -> DWORD {
+        match self {
+            PipeMode::Byte => PIPE_TYPE_BYTE,
+            PipeMode::Message => PIPE_TYPE_MESSAGE,
+        }
+    }
+
+    fn read_mode(self) -> DWORD {
+        match self {
+            PipeMode::Byte => PIPE_READMODE_BYTE,
+            PipeMode::Message => PIPE_READMODE_MESSAGE,
+        }
+    }
+}
+
+/// The direction data flows through a pipe, as seen by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeAccess {
+    /// Both ends can read and write.
+    Duplex,
+    /// Data flows from the client to the server only.
+    Inbound,
+    /// Data flows from the server to the client only.
+    Outbound,
+}
+
+impl PipeAccess {
+    fn server_access(self) -> DWORD {
+        match self {
+            PipeAccess::Duplex => PIPE_ACCESS_DUPLEX,
+            PipeAccess::Inbound => PIPE_ACCESS_INBOUND,
+            PipeAccess::Outbound => PIPE_ACCESS_OUTBOUND,
+        }
+    }
+
+    // a one-way client also needs the attribute access it lacks, to query
+    // and change the pipe's state.
+    fn client_access(self) -> DWORD {
+        match self {
+            PipeAccess::Duplex => GENERIC_READ | GENERIC_WRITE,
+            PipeAccess::Inbound => GENERIC_WRITE | FILE_READ_ATTRIBUTES,
+            PipeAccess::Outbound => GENERIC_READ | FILE_WRITE_ATTRIBUTES,
+        }
+    }
+
+    fn readable(self, server_half: bool) -> bool {
+        match self {
+            PipeAccess::Duplex => true,
+            PipeAccess::Inbound => server_half,
+            PipeAccess::Outbound => !server_half,
+        }
+    }
+
+    fn writable(self, server_half: bool) -> bool {
+        match self {
+            PipeAccess::Duplex => true,
+            PipeAccess::Inbound => !server_half,
+            PipeAccess::Outbound => server_half,
+        }
+    }
+}
+
+/// A security descriptor controlling who may connect to a pipe.
+#[derive(Debug)]
+pub struct SecurityDescriptor {
+    inner: PSECURITY_DESCRIPTOR,
+}
+
+impl SecurityDescriptor {
+    /// Parses a security descriptor from its SDDL string form, e.g.
+    /// `"D:(A;;GA;;;WD)"`.
+    pub fn from_sddl(sddl: &str) -> io::Result<SecurityDescriptor> {
+        let sddl = to_u16s(sddl)?;
+        let mut descriptor = std::ptr::null_mut();
+        let ok = unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(sddl.as_ptr(),
+                                                                 SDDL_REVISION_1,
+                                                                 &mut descriptor,
+                                                                 std::ptr::null_mut())
+        };
+
+        if ok != 0 {
+            Ok(SecurityDescriptor { inner: descriptor })
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Takes ownership of a self-relative security descriptor, which is freed
+    /// with `LocalFree` when the `SecurityDescriptor` is dropped.
+    ///
+    /// # Safety
+    ///
+    /// `descriptor` must be a valid security descriptor allocated with
+    /// `LocalAlloc` that is not used or freed elsewhere.
+    pub unsafe fn from_raw(descriptor: PSECURITY_DESCRIPTOR) -> SecurityDescriptor {
+        SecurityDescriptor { inner: descriptor }
+    }
+
+    fn attributes(&self) -> SECURITY_ATTRIBUTES {
+        SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as DWORD,
+            lpSecurityDescriptor: self.inner,
+            bInheritHandle: FALSE,
+        }
+    }
+}
+
+impl Drop for SecurityDescriptor {
+    fn drop(&mut self) {
+        let _ = unsafe { LocalFree(self.inner) };
+    }
+}
+
+unsafe impl Sync for SecurityDescriptor {}
+unsafe impl Send for SecurityDescriptor {}
+
+/// Configuration for binding a `PipeListener`, applied to every pipe instance
+/// the listener creates. The same builder can bind any number of pipes, and
+/// cloning it copies only the configuration.
+///
+/// ```no_run
+/// use windows_named_pipe::{PipeListenerBuilder, PipeMode};
+///
+/// let listener = PipeListenerBuilder::new()
+///     .mode(PipeMode::Message)
+///     .max_instances(4)
+///     .bind(r"\\.\pipe\example")
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct PipeListenerBuilder {
+    in_buffer_size: u32,
+    out_buffer_size: u32,
+    mode: PipeMode,
+    access: PipeAccess,
+    // shared so every instance the listener creates uses the same descriptor.
+    security: Option<Arc<SecurityDescriptor>>,
+    overlapped: bool,
+    write_through: bool,
+    reject_remote_clients: bool,
+    exclusive: bool,
+    default_timeout: Duration,
+    max_instances: u32,
+    backlog: u32,
+}
+
+impl PipeListenerBuilder {
+    /// Creates a builder with the same configuration as `PipeListener::bind`.
+    pub fn new() -> PipeListenerBuilder {
+        PipeListenerBuilder::default()
+    }
+
+    /// The size in bytes of each instance's input buffer, 65536 by default.
+    pub fn in_buffer_size(&mut self, size: u32) -> &mut Self {
+        self.in_buffer_size = size;
+        self
+    }
+
+    /// The size in bytes of each instance's output buffer, 65536 by default.
+    pub fn out_buffer_size(&mut self, size: u32) -> &mut Self {
+        self.out_buffer_size = size;
+        self
+    }
+
+    /// The type of pipe to create, `PipeMode::Byte` by default. The server
+    /// end of a `PipeMode::Message` pipe reads in message mode.
+    pub fn mode(&mut self, mode: PipeMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// The direction data flows through the pipe, `PipeAccess::Duplex` by
+    /// default.
+    pub fn access(&mut self, access: PipeAccess) -> &mut Self {
+        self.access = access;
+        self
+    }
+
+    /// Secures every instance with `descriptor` instead of the default DACL.
+    pub fn security_descriptor(&mut self, descriptor: SecurityDescriptor) -> &mut Self {
+        self.security = Some(Arc::new(descriptor));
+        self
+    }
+
+    /// Secures every instance with the descriptor described by `sddl`, such
+    /// as `"D:(A;;GA;;;WD)"`, like `SecurityDescriptor::from_sddl`. Fails if
+    /// `sddl` isn't valid, leaving the builder unchanged.
+    pub fn security_descriptor_sddl(&mut self, sddl: &str) -> io::Result<&mut Self> {
+        let descriptor = SecurityDescriptor::from_sddl(sddl)?;
+        Ok(self.security_descriptor(descriptor))
+    }
+
+    /// Whether to open instances for overlapped I/O, false by default.
+    pub fn overlapped(&mut self, overlapped: bool) -> &mut Self {
+        self.overlapped = overlapped;
+        self
+    }
+
+    /// Whether to create instances with `FILE_FLAG_WRITE_THROUGH`, false by
+    /// default. Writes to a client on another computer then do not complete
+    /// until the data is in the client's buffer, trading latency for the
+    /// guarantee that nothing written is still cached locally.
+    pub fn write_through(&mut self, write_through: bool) -> &mut Self {
+        self.write_through = write_through;
+        self
+    }
+
+    /// Whether to create instances with `PIPE_REJECT_REMOTE_CLIENTS`, false
+    /// by default. Clients on other computers then can't connect at all,
+    /// which is a good idea for a pipe only meant for local processes.
+    pub fn reject_remote_clients(&mut self, reject: bool) -> &mut Self {
+        self.reject_remote_clients = reject;
+        self
+    }
+
+    /// Whether `bind` fails if the pipe already exists, true by default.
+    /// Several non-exclusive listeners, in one process or many, can serve the
+    /// same name: each client connects to whichever listening instance
+    /// Windows picks, so clients can't choose a server. All the listeners
+    /// must use the same mode and access, and `max_instances` limits the
+    /// instances of all of them together.
+    pub fn exclusive(&mut self, exclusive: bool) -> &mut Self {
+        self.exclusive = exclusive;
+        self
+    }
+
+    /// The timeout clients use when waiting for an instance with
+    /// `NMPWAIT_USE_DEFAULT_WAIT`, 50ms by default.
+    pub fn default_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.default_timeout = timeout;
+        self
+    }
+
+    /// The maximum number of instances of the pipe that can exist at once,
+    /// between 1 and 255. The default of 255 means unlimited. Once the limit
+    /// is reached, connecting clients and `accept` fail with
+    /// `ERROR_PIPE_BUSY` until an instance is freed.
+    pub fn max_instances(&mut self, max_instances: u32) -> &mut Self {
+        self.max_instances = max_instances;
+        self
+    }
+
+    /// How many instances to keep waiting for clients, 1 by default. While
+    /// the server is busy with one client, up to this many more can connect
+    /// without seeing `ERROR_PIPE_BUSY`. Limited by `max_instances`.
+    pub fn backlog(&mut self, backlog: u32) -> &mut Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Creates a listener for the pipe at `path` using this configuration.
+    /// Fails with `io::ErrorKind::AddrInUse` if another listener already
+    /// created the pipe, unless the listener isn't `exclusive`. The OS error
+    /// behind it, `ERROR_ACCESS_DENIED`, is the error's inner error.
+    pub fn bind<P: AsRef<Path>>(&self, path: P) -> io::Result<PipeListener> {
+        if !(1..=PIPE_UNLIMITED_INSTANCES).contains(&self.max_instances) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "max_instances must be between 1 and 255"));
+        }
+        if self.backlog == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "backlog must be at least 1"));
+        }
+
+        let name = Arc::from(path.as_ref().as_os_str());
+        let path = PipeName::from_path(path.as_ref())?.to_wide()?;
+        let handle = PipeListener::create_pipe(&path, true, self).map_err(|err| {
+            match err.raw_os_error().map(|x| x as u32) {
+                // FILE_FLAG_FIRST_PIPE_INSTANCE found an existing instance.
+                // Otherwise the existing pipe's security or access doesn't
+                // allow another instance, which the OS error says.
+                Some(ERROR_ACCESS_DENIED) if self.exclusive => {
+                    io::Error::new(io::ErrorKind::AddrInUse, err)
+                }
+                _ => err,
+            }
+        })?;
+        let mut listener = PipeListener {
+            path: path,
+            name,
+            spares: VecDeque::new(),
+            connected: None,
+            options: self.clone(),
+            shutdown: Arc::new(ShutdownState {
+                shutdown: AtomicBool::new(false),
+                waiting: Mutex::new(None),
+            }),
+        };
+        listener.spares.push_back(handle);
+        listener.fill_spares()?;
+        Ok(listener)
+    }
+}
+
+impl Default for PipeListenerBuilder {
+    fn default() -> PipeListenerBuilder {
+        PipeListenerBuilder {
+            in_buffer_size: 65536,
+            out_buffer_size: 65536,
+            mode: PipeMode::Byte,
+            access: PipeAccess::Duplex,
+            security: None,
+            overlapped: false,
+            write_through: false,
+            reject_remote_clients: false,
+            exclusive: true,
+            default_timeout: Duration::from_millis(50),
+            max_instances: PIPE_UNLIMITED_INSTANCES,
+            backlog: 1,
+        }
+    }
+}
+
+/// Copies everything read from `src` to `dst` until `src` reaches the end of
+/// the stream, returning the number of bytes copied. The same as
+/// `src.copy_to(dst)`: writes to `dst` fail once its peer goes away.
+pub fn pipe_forward(src: &mut PipeStream, dst: &mut PipeStream) -> io::Result<u64> {
+    src.copy_to(dst)
+}
+
+/// Blocks until one of `streams` has data to read or its other end has gone
+/// away, returning the index of a stream that is ready. Every stream must
+/// have been opened for overlapped I/O. Fails with `io::ErrorKind::TimedOut`
+/// if `timeout` passes first.
+///
+/// At most 64 streams can be waited on at once, the limit of
+/// `WaitForMultipleObjects`.
+pub fn wait_readable(streams: &[&PipeStream], timeout: Option<Duration>) -> io::Result<usize> {
+    if streams.is_empty() || streams.len() > MAXIMUM_WAIT_OBJECTS as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "can only wait on between 1 and 64 streams"));
+    }
+    for stream in streams {
+        stream.check_overlapped()?;
+        stream.check_readable()?;
+    }
+
+    let events = streams.iter().map(|_| create_event()).collect::<io::Result<Vec<_>>>()?;
+    let raw_events: Vec<HANDLE> = events.iter().map(|event| event.inner).collect();
+    let mut overlapped: Vec<OVERLAPPED> = events.iter().map(new_overlapped).collect();
+    let mut pending = vec![false; streams.len()];
+
+    // a zero byte read completes once there is data, without consuming any.
+    let mut zero = [0u8; 1];
+    let mut ready = None;
+    for (i, stream) in streams.iter().enumerate() {
+        let ok = unsafe {
+            ReadFile(stream.handle.inner,
+                     zero.as_mut_ptr() as LPVOID,
+                     0,
+                     std::ptr::null_mut(),
+                     &mut overlapped[i])
+        };
+        if ok == 0 && unsafe { GetLastError() } == ERROR_IO_PENDING {
+            pending[i] = true;
+        } else {
+            // data, or an error such as the pipe being broken, straight away.
+            ready = Some(i);
+            break;
+        }
+    }
+
+    let result = match ready {
+        Some(i) => Ok(i),
+        None => {
+            let ms = timeout.map_or(INFINITE, duration_to_ms);
+            let ret = unsafe {
+                WaitForMultipleObjects(raw_events.len() as DWORD, raw_events.as_ptr(), FALSE, ms)
+            };
+            if ret < WAIT_OBJECT_0 + raw_events.len() as DWORD {
+                Ok((ret - WAIT_OBJECT_0) as usize)
+            } else if ret == WAIT_TIMEOUT {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "no pipe became readable"))
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        }
+    };
+
+    // the reads still pending must finish before their OVERLAPPEDs are freed.
+    for (i, stream) in streams.iter().enumerate() {
+        if pending[i] {
+            let mut bytes = 0;
+            unsafe {
+                CancelIoEx(stream.handle.inner, &mut overlapped[i]);
+                GetOverlappedResult(stream.handle.inner, &mut overlapped[i], &mut bytes, TRUE);
+            }
+        }
+    }
+
+    result
+}
+
+/// Connects to a message-type pipe, writes `out` as a message, reads the
+/// reply into `in_buf` and disconnects, returning the length of the reply.
+/// Waits up to `timeout` for an instance of the pipe to become available.
+///
+/// Fails with `io::ErrorKind::TimedOut` if the pipe stays busy. As with
+/// `PipeStream::transact`, a reply that doesn't fit in `in_buf` fails with the
+/// raw OS error `ERROR_MORE_DATA`.
+pub fn call_named_pipe<P: AsRef<Path>>(path: P,
+                                       out: &[u8],
+                                       in_buf: &mut [u8],
+                                       timeout: Duration)
+                                       -> io::Result<usize> {
+    let path = PipeName::from_path(path.as_ref())?.to_wide()?;
+    // 0 would mean the server's default timeout.
+    let timeout = std::cmp::max(duration_to_ms(timeout), NMPWAIT_NOWAIT);
+    let mut bytes_read = 0;
+    let ok = unsafe {
+        CallNamedPipeW(path.as_ptr(),
+                       out.as_ptr() as LPVOID,
+                       out.len() as DWORD,
+                       in_buf.as_mut_ptr() as LPVOID,
+                       in_buf.len() as DWORD,
+                       &mut bytes_read,
+                       timeout)
+    };
+
+    if ok != 0 {
+        return Ok(bytes_read as usize);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error().map(|x| x as u32) {
+        Some(ERROR_SEM_TIMEOUT) => {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for pipe"))
+        }
+        Some(ERROR_PIPE_BUSY) => {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "all pipe instances stayed busy"))
+        }
+        _ => Err(err),
+    }
+}
+
+/// Which end of the pipe `connect_or_create` ended up with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Another peer already owned the pipe and this one connected to it.
+    Client,
+    /// No pipe existed, so this peer created it and accepted a connection.
+    Server,
+}
+
+/// Connects to the pipe at `path` if a peer has already created it, or
+/// otherwise creates it and waits for a peer to connect. Creating the pipe
+/// uses `FILE_FLAG_FIRST_PIPE_INSTANCE`, so if two peers race only one
+/// becomes the server and the other connects to it.
+///
+/// The pipe allows a single instance, so a third peer gets `ERROR_PIPE_BUSY`.
+/// If the pipe keeps disappearing before it can be connected to and
+/// reappearing before it can be created, gives up after a few tries with
+/// the error from the last one.
+pub fn connect_or_create<P: AsRef<Path>>(path: P) -> io::Result<(PipeStream, Role)> {
+    let path = path.as_ref();
+    let mut lost = 0;
+    loop {
+        match PipeStream::connect(path) {
+            Ok(stream) => return Ok((stream, Role::Client)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let err = match PipeListenerBuilder::new().max_instances(1).bind(path) {
+            Ok(mut listener) => return Ok((listener.accept_one()?, Role::Server)),
+            Err(err) => err,
+        };
+        // another peer created the pipe first; connect to it.
+        if err.kind() != io::ErrorKind::AddrInUse &&
+           err.raw_os_error() != Some(ERROR_PIPE_BUSY as i32) {
+            return Err(err);
+        }
+
+        // the pipe can keep coming and going between the connect and the
+        // bind, so only race so many times.
+        lost += 1;
+        if lost == CONNECT_OR_CREATE_ATTEMPTS {
+            return Err(err);
+        }
+        thread::sleep(Backoff::default().delay(lost));
+    }
+}
+
+// how many times `connect_or_create` tries before giving up.
+const CONNECT_OR_CREATE_ATTEMPTS: u32 = 10;
+
+/// Runs a server for the pipe at `path`, calling `handler` with each
+/// connection on a thread of its own, until `shutdown` is set. Streams are
+/// opened for overlapped I/O. Once `shutdown` is seen the pipe is closed to
+/// new clients and `serve` waits for the running handlers to return.
+///
+/// For anything more involved, such as limiting the number of handler
+/// threads, use `PipeListener::serve` or accept in a loop.
+pub fn serve<P, F>(path: P, handler: F, shutdown: &AtomicBool) -> io::Result<()>
+    where P: AsRef<Path>,
+          F: Fn(PipeStream) + Sync
+{
+    let handler = &handler;
+    thread::scope(|scope| {
+        let mut listener = PipeListenerBuilder::new().overlapped(true).bind(path)?;
+        listener.serve(shutdown, |stream| {
+            scope.spawn(move || handler(stream));
+        })
+    })
+}
+
+/// Whether connecting again later might succeed where the connect that
+/// returned `err` failed. True for exactly these OS errors:
+///
+/// * `ERROR_FILE_NOT_FOUND`, the server hasn't created the pipe yet or is
+///   between instances.
+/// * `ERROR_PIPE_BUSY`, every instance is connected to another client.
+/// * `ERROR_ACCESS_DENIED`, which a client can see while a server is
+///   starting up and replacing an instance left by its predecessor. A
+///   server that really denies access keeps failing, so retry loops should
+///   give up eventually, as should those connecting with a `PipeAccess`
+///   the pipe doesn't allow, which gets the same error.
+///
+/// Other errors made by this crate, such as `io::ErrorKind::TimedOut` from
+/// `connect_timeout`, are never retryable.
+pub fn is_retryable(err: &io::Error) -> bool {
+    matches!(err.raw_os_error().map(|x| x as u32),
+             Some(ERROR_FILE_NOT_FOUND) | Some(ERROR_PIPE_BUSY) | Some(ERROR_ACCESS_DENIED))
+}
+
+/// The symbolic name of a Win32 error code that pipe operations commonly
+/// fail with, such as `"ERROR_PIPE_BUSY"` for 231.
+pub fn error_name(code: u32) -> Option<&'static str> {
+    macro_rules! names {
+        ($($name:ident),*) => {
+            match code {
+                $($name => Some(stringify!($name)),)*
+                _ => None,
+            }
+        }
+    }
+
+    names!(ERROR_FILE_NOT_FOUND,
+           ERROR_ACCESS_DENIED,
+           ERROR_INVALID_HANDLE,
+           ERROR_NOT_ENOUGH_MEMORY,
+           ERROR_BAD_NETPATH,
+           ERROR_BAD_NET_NAME,
+           ERROR_INVALID_PARAMETER,
+           ERROR_BROKEN_PIPE,
+           ERROR_SEM_TIMEOUT,
+           ERROR_INSUFFICIENT_BUFFER,
+           ERROR_INVALID_NAME,
+           ERROR_ALREADY_EXISTS,
+           ERROR_PIPE_LOCAL,
+           ERROR_BAD_PIPE,
+           ERROR_PIPE_BUSY,
+           ERROR_NO_DATA,
+           ERROR_PIPE_NOT_CONNECTED,
+           ERROR_MORE_DATA,
+           ERROR_PIPE_CONNECTED,
+           ERROR_PIPE_LISTENING,
+           ERROR_OPERATION_ABORTED,
+           ERROR_IO_INCOMPLETE,
+           ERROR_IO_PENDING,
+           ERROR_NOT_FOUND)
+}
+
+/// Displays `err` with the symbolic name of its Win32 error code in front,
+/// e.g. `ERROR_PIPE_BUSY (231): All pipe instances are busy. (os error 231)`,
+/// for logging. Errors without a known code display as usual. The error
+/// itself is untouched, so `raw_os_error` still works on it.
+pub fn display_error<'a>(err: &'a io::Error) -> ErrorDisplay<'a> {
+    ErrorDisplay { err }
+}
+
+/// The `Display` adapter returned by `display_error`.
+#[derive(Debug)]
+pub struct ErrorDisplay<'a> {
+    err: &'a io::Error,
+}
+
+impl<'a> fmt::Display for ErrorDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.err.raw_os_error().map(|x| x as u32);
+        match code.and_then(error_name) {
+            Some(name) => write!(f, "{} ({}): {}", name, code.unwrap(), self.err),
+            None => self.err.fmt(f),
+        }
+    }
+}
+
+fn duration_to_ms(duration: Duration) -> DWORD {
+    // round up so that short non-zero durations don't become 0, which Windows
+    // often treats specially.
+    let ms = duration.as_secs()
+        .saturating_mul(1000)
+        .saturating_add(duration.subsec_nanos().div_ceil(1_000_000) as u64);
+    std::cmp::min(ms, (INFINITE - 1) as u64) as DWORD
+}
+
+    fn to_u16s<S: AsRef<OsStr>>(s: S) -> io::Result<Vec<u16>> {
+        fn inner(s: &OsStr) -> io::Result<Vec<u16>> {
+            let mut maybe_result: Vec<u16> = s.encode_wide().collect();
+            if maybe_result.iter().any(|&u| u == 0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "strings passed to WinAPI cannot contain NULs"));
+            }
+            maybe_result.push(0);
+            Ok(maybe_result)
+        }
+        inner(s.as_ref())
+    }
+
+impl PipeListener {
+    fn create_pipe(path: &[u16],
+                   first: bool,
+                   options: &PipeListenerBuilder)
+                   -> io::Result<Handle> {
+        let mut access_flags = options.access.server_access();
+        if first && options.exclusive {
+            access_flags |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+        }
+        if options.overlapped {
+            access_flags |= FILE_FLAG_OVERLAPPED;
+        }
+        if options.write_through {
+            access_flags |= FILE_FLAG_WRITE_THROUGH;
+        }
+        let mut pipe_mode = options.mode.pipe_type() | options.mode.read_mode() | PIPE_WAIT;
+        if options.reject_remote_clients {
+            pipe_mode |= PIPE_REJECT_REMOTE_CLIENTS;
+        }
+        let mut attributes = options.security.as_ref().map(|sd| sd.attributes());
+        let attributes_ptr = attributes.as_mut()
+            .map_or(std::ptr::null_mut(), |sa| sa as *mut SECURITY_ATTRIBUTES);
+        let handle = unsafe {
+            CreateNamedPipeW(path.as_ptr(),
+                             access_flags,
+                             pipe_mode,
+                             options.max_instances,
+                             options.out_buffer_size,
+                             options.in_buffer_size,
+                             duration_to_ms(options.default_timeout),
+                             attributes_ptr)
+        };
+
+        if handle != INVALID_HANDLE_VALUE {
+            Ok(Handle { inner: handle })
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn connect_pipe(handle: &Handle,
+                    overlapped: bool,
+                    timeout: Option<Duration>)
+                    -> io::Result<()> {
+        let mut bytes = 0;
+        let result = run_io(handle, overlapped, timeout, &mut bytes, |_, overlapped| unsafe {
+            ConnectNamedPipe(handle.inner, overlapped)
+        });
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => match err.raw_os_error().map(|x| x as u32) {
+                Some(ERROR_PIPE_CONNECTED) => Ok(()),
+                _ => Err(err),
+            },
+        }
+    }
+
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        PipeListenerBuilder::new().bind(path)
+    }
+
+    /// Like `bind`, but with explicit sizes in bytes for the input and output
+    /// buffers of every pipe instance the listener creates.
+    pub fn bind_with_buffer_sizes<P: AsRef<Path>>(path: P,
+                                                  in_buffer_size: u32,
+                                                  out_buffer_size: u32)
+                                                  -> io::Result<Self> {
+        PipeListenerBuilder::new()
+            .in_buffer_size(in_buffer_size)
+            .out_buffer_size(out_buffer_size)
+            .bind(path)
+    }
+
+    /// Like `bind`, but creates pipes of the given type. See
+    /// `PipeListenerBuilder::mode`.
+    pub fn bind_with_mode<P: AsRef<Path>>(path: P, mode: PipeMode) -> io::Result<Self> {
+        PipeListenerBuilder::new().mode(mode).bind(path)
+    }
+
+    /// Like `bind`, but creates one-directional pipes when `access` is not
+    /// `PipeAccess::Duplex`. Clients should connect with the same `access`.
+    pub fn bind_with_access<P: AsRef<Path>>(path: P, access: PipeAccess) -> io::Result<Self> {
+        PipeListenerBuilder::new().access(access).bind(path)
+    }
+
+    /// Like `bind`, but every pipe instance the listener creates is secured
+    /// with `descriptor` instead of the default DACL.
+    pub fn bind_with_security<P: AsRef<Path>>(path: P,
+                                              descriptor: SecurityDescriptor)
+                                              -> io::Result<Self> {
+        PipeListenerBuilder::new().security_descriptor(descriptor).bind(path)
+    }
+
+    /// Like `bind`, but sets the default timeout clients use when waiting for
+    /// an instance. See `PipeListenerBuilder::default_timeout`.
+    pub fn bind_with_default_timeout<P: AsRef<Path>>(path: P,
+                                                     default_timeout: Duration)
+                                                     -> io::Result<Self> {
+        PipeListenerBuilder::new().default_timeout(default_timeout).bind(path)
+    }
+
+    /// Like `bind`, but limits how many instances of the pipe can exist. See
+    /// `PipeListenerBuilder::max_instances`.
+    pub fn bind_with_max_instances<P: AsRef<Path>>(path: P,
+                                                   max_instances: u32)
+                                                   -> io::Result<Self> {
+        PipeListenerBuilder::new().max_instances(max_instances).bind(path)
+    }
+
+    /// Creates a listener for the pipe at `path` configured the same way as
+    /// this listener. No handles are shared with this listener.
+    pub fn bind_with_same_options<P: AsRef<Path>>(&self, path: P) -> io::Result<PipeListener> {
+        self.options.bind(path)
+    }
+
+    /// Like `bind`, but keeps `backlog` instances waiting for clients. See
+    /// `PipeListenerBuilder::backlog`.
+    pub fn bind_with_backlog<P: AsRef<Path>>(path: P, backlog: u32) -> io::Result<Self> {
+        PipeListenerBuilder::new().backlog(backlog).bind(path)
+    }
+
+    /// Like `bind`, but creates pipes opened for overlapped I/O. Accepted
+    /// streams support `PipeStream::read_overlapped` and
+    /// `PipeStream::write_overlapped`.
+    pub fn bind_overlapped<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        PipeListenerBuilder::new().overlapped(true).bind(path)
+    }
+
+    /// Blocks until a client connects, without returning a stream for it.
+    /// The connected instance is returned by the next `accept` or
+    /// `accept_one`, and until then calling `wait_connect` again returns
+    /// immediately.
+    pub fn wait_connect(&mut self) -> io::Result<()> {
+        self.connect_next(None)
+    }
+
+    /// Like `accept`, but gives up and returns `Ok(None)` if no client
+    /// connects within `timeout`, so a server loop can check whether it
+    /// should stop. The instance that was waiting is kept for the next
+    /// accept. The listener must have been bound with
+    /// `PipeListenerBuilder::overlapped`.
+    pub fn accept_timeout(&mut self, timeout: Duration) -> io::Result<Option<PipeStream>> {
+        if !self.options.overlapped {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "accept_timeout requires an overlapped listener"));
+        }
+
+        match self.connect_next(Some(timeout)) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::TimedOut => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let handle = self.connected.take().expect("connect_next connected an instance");
+
+        Ok(Some(self.server_stream(handle)))
+    }
+
+    /// Accepts connections and passes each to `handler` on this thread until
+    /// `shutdown` is set, which is checked at least every 100ms. Errors that
+    /// only affect one connection are skipped, backing off as `incoming_ok`
+    /// does, and any other error is returned. The listener must have been
+    /// bound with `PipeListenerBuilder::overlapped`. See also `serve`.
+    pub fn serve<F>(&mut self, shutdown: &AtomicBool, mut handler: F) -> io::Result<()>
+        where F: FnMut(PipeStream)
+    {
+        let mut failures = 0;
+        while !shutdown.load(Ordering::SeqCst) {
+            match self.accept_timeout(SERVE_POLL_INTERVAL) {
+                Ok(Some(stream)) => {
+                    failures = 0;
+                    handler(stream);
+                }
+                Ok(None) => {}
+                Err(ref err) if !is_fatal_accept_error(err) => {
+                    failures += 1;
+                    let delay = accept_backoff(failures);
+                    thread::sleep(std::cmp::min(delay, SERVE_POLL_INTERVAL));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    // Connects the next instance to a client, unless one is already
+    // connected. An instance whose connect timed out goes back to the front
+    // of the spares.
+    fn connect_next(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        if self.connected.is_some() {
+            return Ok(());
+        }
+
+        let handle = match self.spares.pop_front() {
+            Some(handle) => handle,
+            None => PipeListener::create_pipe(&self.path, false, &self.options)?,
+        };
+
+        if let Err(err) = self.fill_spares() {
+            self.spares.push_front(handle);
+            return Err(err);
+        }
+
+        if let Err(err) = self.connect_instance(&handle, timeout) {
+            self.recycle(handle, &err);
+            return Err(err);
+        }
+
+        self.connected = Some(handle);
+        Ok(())
+    }
+
+    /// Blocks until a client connects and returns a stream for it. A client
+    /// that `wait_connect` already waited for is returned straight away.
+    pub fn accept(&mut self) -> io::Result<PipeStream> {
+        self.wait_connect()?;
+        let handle = self.connected.take().expect("wait_connect connected an instance");
+
+        Ok(self.server_stream(handle))
+    }
+
+    /// Like `accept`, but does not create a spare instance for the next client
+    /// first. The instance is created when `accept_one` or `accept` next needs
+    /// one, so a server handling clients one at a time keeps a single
+    /// instance and clients arriving in between see `ERROR_PIPE_BUSY`.
+    pub fn accept_one(&mut self) -> io::Result<PipeStream> {
+        if let Some(handle) = self.connected.take() {
+            return Ok(self.server_stream(handle));
+        }
+
+        let handle = match self.spares.pop_front() {
+            Some(handle) => handle,
+            None => PipeListener::create_pipe(&self.path, false, &self.options)?,
+        };
+
+        if let Err(err) = self.connect_instance(&handle, None) {
+            self.recycle(handle, &err);
+            return Err(err);
+        }
+
+        Ok(self.server_stream(handle))
+    }
+
+    fn server_stream(&self, handle: Handle) -> PipeStream {
+        let mut stream =
+            PipeStream::new(handle, true, self.options.access, self.options.overlapped);
+        stream.path = Some(self.name.clone());
+        stream
+    }
+
+    // Puts an instance whose connect failed with `err` back at the front of
+    // the spares if it can take another client, so a failed accept doesn't
+    // use up an instance.
+    fn recycle(&mut self, handle: Handle, err: &io::Error) {
+        match err.kind() {
+            // the connect was cancelled, so the instance is still listening.
+            io::ErrorKind::TimedOut |
+            io::ErrorKind::ConnectionAborted => self.spares.push_front(handle),
+            // e.g. ERROR_NO_DATA, a client came and went before the connect,
+            // which leaves the instance needing a disconnect before reuse.
+            _ => {
+                if unsafe { DisconnectNamedPipe(handle.inner) } != 0 {
+                    self.spares.push_front(handle);
+                }
+            }
+        }
+    }
+
+    /// A handle that can stop the listener from another thread, waking an
+    /// accept that is waiting for a client. See `ShutdownHandle::signal`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle { state: self.shutdown.clone() }
+    }
+
+    // Waits for a client to connect to `handle`, where a ShutdownHandle can
+    // cancel the wait.
+    fn connect_instance(&self, handle: &Handle, timeout: Option<Duration>) -> io::Result<()> {
+        {
+            let mut waiting = self.shutdown.waiting.lock().unwrap();
+            if self.shutdown.shutdown.load(Ordering::Acquire) {
+                return Err(shutdown_error());
+            }
+            *waiting = Some(handle.inner as usize);
+        }
+
+        let result = PipeListener::connect_pipe(handle, self.options.overlapped, timeout);
+        *self.shutdown.waiting.lock().unwrap() = None;
+
+        match result {
+            Err(ref err) if err.raw_os_error() == Some(ERROR_OPERATION_ABORTED as i32) &&
+                            self.shutdown.shutdown.load(Ordering::Acquire) => {
+                Err(shutdown_error())
+            }
+            result => result,
+        }
+    }
+
+    // Creates spare instances until there are `backlog` of them. If every
+    // instance allowed by max_instances exists, goes with fewer until the
+    // next accept.
+    fn fill_spares(&mut self) -> io::Result<()> {
+        while self.spares.len() < self.options.backlog as usize {
+            match PipeListener::create_pipe(&self.path, false, &self.options) {
+                Ok(handle) => self.spares.push_back(handle),
+                Err(ref err) if err.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn incoming<'a>(&'a mut self) -> Incoming<'a> {
+        Incoming { listener: self }
+    }
+
+    /// Like `incoming`, but pairs each connection with the client's process
+    /// id, queried as soon as it connects. The id is `None` if it couldn't
+    /// be queried, which doesn't fail the accept.
+    pub fn incoming_with_pid<'a>(&'a mut self) -> IncomingWithPid<'a> {
+        IncomingWithPid { listener: self }
+    }
+
+    /// Like `incoming`, but takes ownership of the listener so the iterator
+    /// can be moved into another thread.
+    pub fn into_incoming(self) -> IntoIncoming {
+        IntoIncoming { listener: self }
+    }
+
+    /// An iterator over only the successful connections to the listener.
+    /// Errors that only affect one connection, such as a client that went
+    /// away before it was accepted, are skipped. An error that will recur on
+    /// every accept, such as an invalid handle or access being denied, ends
+    /// the iteration instead of spinning on it. After each skipped error the
+    /// next accept waits a little longer, from 10ms up to a second, so an
+    /// error that comes back straight away, like `ERROR_PIPE_BUSY` once
+    /// `max_instances` instances are connected, doesn't spin either.
+    pub fn incoming_ok<'a>(&'a mut self) -> IncomingOk<'a> {
+        IncomingOk {
+            listener: self,
+            on_error: Box::new(|_| {}),
+            failures: 0,
+            done: false,
+        }
+    }
+}
+
+pub struct Incoming<'a>
+{
+    listener: &'a mut PipeListener,
+}
+
+impl<'a> IntoIterator for &'a mut PipeListener {
+    type Item = io::Result<PipeStream>;
+    type IntoIter = Incoming<'a>;
+
+    fn into_iter(self) -> Incoming<'a> {
+        self.incoming()
+    }
+}
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = io::Result<PipeStream>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept())
+    }
+}
+
+/// The iterator returned by `PipeListener::incoming_with_pid`.
+#[derive(Debug)]
+pub struct IncomingWithPid<'a> {
+    listener: &'a mut PipeListener,
+}
+
+impl<'a> Iterator for IncomingWithPid<'a> {
+    type Item = io::Result<(PipeStream, Option<u32>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept().map(|stream| {
+            let pid = stream.client_process_id().ok();
+            (stream, pid)
+        }))
+    }
+}
+
+/// The iterator returned by `PipeListener::incoming_ok`.
+pub struct IncomingOk<'a> {
+    listener: &'a mut PipeListener,
+    on_error: Box<dyn FnMut(&io::Error) + 'a>,
+    // errors since the last successful accept.
+    failures: u32,
+    done: bool,
+}
+
+impl<'a> IncomingOk<'a> {
+    /// Calls `f` with every error accept returns, including the one that
+    /// ends the iteration.
+    pub fn on_error<F: FnMut(&io::Error) + 'a>(mut self, f: F) -> IncomingOk<'a> {
+        self.on_error = Box::new(f);
+        self
+    }
+}
+
+impl<'a> Iterator for IncomingOk<'a> {
+    type Item = PipeStream;
+
+    fn next(&mut self) -> Option<PipeStream> {
+        while !self.done {
+            let err = match self.listener.accept() {
+                Ok(stream) => {
+                    self.failures = 0;
+                    return Some(stream);
+                }
+                Err(err) => err,
+            };
+
+            (self.on_error)(&err);
+            self.done = is_fatal_accept_error(&err);
+            if !self.done {
+                self.failures += 1;
+                thread::sleep(accept_backoff(self.failures));
+            }
+        }
+        None
+    }
+}
+
+// How long to wait before accepting again after the `failures`th error in a
+// row that didn't stop accepting.
+fn accept_backoff(failures: u32) -> Duration {
+    Backoff::default().delay(failures)
+}
+
+// how long `drain_timeout` waits between attempts to cancel its flush.
+const DRAIN_CANCEL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn drain_thread_lost() -> io::Error {
+    io::Error::other("the drain thread exited without a result")
+}
+
+// how often `serve` checks whether it should stop.
+const SERVE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn shutdown_error() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "the listener was shut down")
+}
+
+// whether accepting again would just fail the same way.
+fn is_fatal_accept_error(err: &io::Error) -> bool {
+    match err.raw_os_error().map(|x| x as u32) {
+        Some(ERROR_INVALID_HANDLE) |
+        Some(ERROR_ACCESS_DENIED) |
+        Some(ERROR_INVALID_NAME) |
+        Some(ERROR_INVALID_PARAMETER) => true,
+        Some(_) => false,
+        // errors made by this crate come from bad arguments.
+        None => true,
+    }
+}
+
+/// An iterator over the connections to a listener it owns, returned by
+/// `PipeListener::into_incoming`.
+#[derive(Debug)]
+pub struct IntoIncoming {
+    listener: PipeListener,
+}
+
+impl IntoIncoming {
+    /// Returns the listener back.
+    pub fn into_inner(self) -> PipeListener {
+        self.listener
+    }
+}
+
+impl IntoIterator for PipeListener {
+    type Item = io::Result<PipeStream>;
+    type IntoIter = IntoIncoming;
+
+    fn into_iter(self) -> IntoIncoming {
+        self.into_incoming()
+    }
+}
+
+impl Iterator for IntoIncoming {
+    type Item = io::Result<PipeStream>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use super::*;
+
+    macro_rules! or_panic {
+        ($e:expr) => {
+            match $e {
+                Ok(e) => e,
+                Err(e) => {
+                    panic!("{}", e);
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn basic() {
+        let socket_path = Path::new("//./pipe/basicsock");
+        println!("{:?}", socket_path);
         let msg1 = b"hello";
         let msg2 = b"world!";
 
@@ -265,22 +4116,2805 @@ mod test {
         let thread = thread::spawn(move || {
             let mut stream = or_panic!(listener.accept());
             let mut buf = [0; 5];
-            or_panic!(stream.read(&mut buf));
-            assert_eq!(&msg1[..], &buf[..]);
-            or_panic!(stream.write_all(msg2));
+            or_panic!(stream.read(&mut buf));
+            assert_eq!(&msg1[..], &buf[..]);
+            or_panic!(stream.write_all(msg2));
+        });
+
+        let mut stream = or_panic!(PipeStream::connect(socket_path));
+
+        or_panic!(stream.write_all(msg1));
+        let mut buf = vec![];
+        or_panic!(stream.read_to_end(&mut buf));
+        assert_eq!(&msg2[..], &buf[..]);
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn buffer_sizes() {
+        let socket_path = Path::new("//./pipe/buffersizessock");
+        let msg = vec![7u8; 4096];
+        let expected = msg.clone();
+
+        let mut listener = or_panic!(PipeListener::bind_with_buffer_sizes(socket_path, 512, 512));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(&msg));
+        });
+
+        let mut stream = or_panic!(PipeStream::connect(socket_path));
+        let mut buf = vec![];
+        or_panic!(stream.read_to_end(&mut buf));
+        assert_eq!(expected, buf);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn message_mode() {
+        let socket_path = Path::new("//./pipe/messagemodesock");
+
+        let mut listener = or_panic!(PipeListener::bind_with_mode(socket_path, PipeMode::Message));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = [0; 16];
+            let n = or_panic!(stream.read(&mut buf));
+            assert_eq!(&b"hello"[..], &buf[..n]);
+            let n = or_panic!(stream.read(&mut buf));
+            assert_eq!(&b"world!"[..], &buf[..n]);
+        });
+
+        let mut stream = or_panic!(PipeStream::connect(socket_path));
+        or_panic!(stream.write_all(b"hello"));
+        or_panic!(stream.write_all(b"world!"));
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn inbound_access() {
+        let socket_path = Path::new("//./pipe/inboundsock");
+
+        let mut listener = or_panic!(PipeListener::bind_with_access(socket_path,
+                                                                    PipeAccess::Inbound));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            assert!(stream.write(b"nope").is_err());
+            let mut buf = vec![];
+            or_panic!(stream.read_to_end(&mut buf));
+            assert_eq!(&b"hello"[..], &buf[..]);
+        });
+
+        let mut stream = or_panic!(PipeStream::connect_with_access(socket_path,
+                                                                   PipeAccess::Inbound));
+        assert!(stream.read(&mut [0; 5]).is_err());
+        or_panic!(stream.write_all(b"hello"));
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn bind_twice() {
+        let socket_path = Path::new("//./pipe/bindtwicesock");
+
+        let _listener = or_panic!(PipeListener::bind(socket_path));
+        let err = PipeListener::bind(socket_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        let inner = err.get_ref().and_then(|e| e.downcast_ref::<io::Error>()).unwrap();
+        assert_eq!(Some(ERROR_ACCESS_DENIED as i32), inner.raw_os_error());
+    }
+
+    #[test]
+    fn outbound_access() {
+        let socket_path = Path::new("//./pipe/outboundsock");
+
+        let mut listener = or_panic!(PipeListener::bind_with_access(socket_path,
+                                                                    PipeAccess::Outbound));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let err = PipeStream::connect_timeout(socket_path, Duration::from_secs(5)).unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+
+        let mut stream = or_panic!(PipeStream::connect_with_access(socket_path,
+                                                                   PipeAccess::Outbound));
+        assert!(stream.write(b"nope").is_err());
+        // the extra attribute access lets a read-only client change its mode.
+        or_panic!(stream.set_read_mode(PipeMode::Byte));
+        or_panic!(stream.info());
+        let mut buf = vec![];
+        or_panic!(stream.read_to_end(&mut buf));
+        assert_eq!(&b"hello"[..], &buf[..]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn connect_timeout() {
+        let socket_path = Path::new("//./pipe/connecttimeoutsock");
+
+        let thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let mut listener = or_panic!(PipeListener::bind(socket_path));
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let mut stream = or_panic!(PipeStream::connect_timeout(socket_path,
+                                                               Duration::from_secs(5)));
+        let mut buf = vec![];
+        or_panic!(stream.read_to_end(&mut buf));
+        assert_eq!(&b"hello"[..], &buf[..]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn connect_timeout_expires() {
+        let socket_path = Path::new("//./pipe/connecttimeoutexpiressock");
+
+        let err = PipeStream::connect_timeout(socket_path, Duration::from_millis(50)).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    }
+
+    #[test]
+    fn security_descriptor() {
+        let socket_path = Path::new("//./pipe/securitysock");
+
+        let descriptor = or_panic!(SecurityDescriptor::from_sddl("D:(A;;GA;;;WD)"));
+        let mut listener = or_panic!(PipeListener::bind_with_security(socket_path, descriptor));
+        let thread = thread::spawn(move || for stream in listener.incoming().take(2) {
+            let mut stream = or_panic!(stream);
+            or_panic!(stream.write_all(b"hi"));
+        });
+
+        for _ in 0..2 {
+            let mut stream = or_panic!(PipeStream::connect(socket_path));
+            let mut buf = vec![];
+            or_panic!(stream.read_to_end(&mut buf));
+            assert_eq!(&b"hi"[..], &buf[..]);
+        }
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn invalid_sddl() {
+        assert!(SecurityDescriptor::from_sddl("not sddl").is_err());
+        assert!(PipeListenerBuilder::new().security_descriptor_sddl("not sddl").is_err());
+    }
+
+    #[test]
+    fn security_descriptor_sddl() {
+        let socket_path = Path::new("//./pipe/securitysddlsock");
+
+        let mut listener = or_panic!(or_panic!(PipeListenerBuilder::new()
+                .security_descriptor_sddl("D:(A;;GA;;;WD)"))
+            .backlog(2)
+            .bind(socket_path));
+        let thread = thread::spawn(move || for stream in listener.incoming().take(3) {
+            let mut stream = or_panic!(stream);
+            or_panic!(stream.write_all(b"hi"));
+        });
+
+        // the descriptor outlives the builder and covers every instance.
+        for _ in 0..3 {
+            let mut stream =
+                or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+            let mut buf = vec![];
+            or_panic!(stream.read_to_end(&mut buf));
+            assert_eq!(&b"hi"[..], &buf[..]);
+        }
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn try_clone() {
+        let socket_path = Path::new("//./pipe/tryclonesock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut clone = or_panic!(stream.try_clone());
+            let writer = thread::spawn(move || or_panic!(clone.write_all(b"hello")));
+            writer.join().unwrap();
+
+            // the original is still connected after the clone is dropped.
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(&b"world"[..], &buf[..]);
+        });
+
+        let mut stream = or_panic!(PipeStream::connect(socket_path));
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(&b"hello"[..], &buf[..]);
+        or_panic!(stream.write_all(b"world"));
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn shared() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<PipeStream>();
+        assert_send_sync::<SharedPipeStream>();
+
+        let socket_path = Path::new("//./pipe/sharedsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(&buf, b"hello");
+            or_panic!(stream.write_all(b"world"));
+        });
+
+        let stream =
+            or_panic!(PipeStreamBuilder::new()
+                .overlapped(true)
+                .connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut shared = stream.into_shared();
+        let mut writer = shared.clone();
+        let write = thread::spawn(move || or_panic!(writer.write_all(b"hello")));
+        let mut buf = [0; 5];
+        or_panic!(shared.read_exact(&mut buf));
+        assert_eq!(&buf, b"world");
+        write.join().unwrap();
+
+        let stream = shared.try_unwrap().unwrap();
+        drop(stream);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn split() {
+        let socket_path = Path::new("//./pipe/splitsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let stream = or_panic!(listener.accept());
+            let (mut read, mut write) = stream.split();
+            let reader = thread::spawn(move || {
+                let mut buf = [0; 5];
+                or_panic!(read.read_exact(&mut buf));
+                assert_eq!(&b"hello"[..], &buf[..]);
+                read
+            });
+            or_panic!(write.write_all(b"world"));
+            let read = reader.join().unwrap();
+            let mut stream = or_panic!(read.reunite(write));
+            or_panic!(stream.write_all(b"!"));
+        });
+
+        let mut stream = or_panic!(PipeStream::connect(socket_path));
+        or_panic!(stream.write_all(b"hello"));
+        let mut buf = vec![];
+        or_panic!(stream.read_to_end(&mut buf));
+        assert_eq!(&b"world!"[..], &buf[..]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn split_overlapped() {
+        let socket_path = Path::new("//./pipe/splitoverlappedsock");
+
+        let mut listener = or_panic!(PipeListener::bind_overlapped(socket_path));
+        let thread = thread::spawn(move || {
+            let (mut read, mut write) = or_panic!(listener.accept()).split();
+            // the read is waiting for the reply while the request is written.
+            let reader = thread::spawn(move || {
+                let mut buf = [0; 4];
+                or_panic!(read.read_exact(&mut buf));
+                assert_eq!(b"pong", &buf);
+            });
+            thread::sleep(Duration::from_millis(50));
+            or_panic!(write.write_all(b"ping"));
+            reader.join().unwrap();
+        });
+
+        let mut stream = or_panic!(PipeStreamBuilder::new()
+            .overlapped(true)
+            .connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut buf = [0; 4];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"ping", &buf);
+        or_panic!(stream.write_all(b"pong"));
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn peek() {
+        let socket_path = Path::new("//./pipe/peeksock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let mut stream = or_panic!(PipeStream::connect(socket_path));
+        while or_panic!(stream.bytes_available()) < 5 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut buf = [0; 5];
+        assert_eq!(5, or_panic!(stream.peek(&mut buf)));
+        assert_eq!(&b"hello"[..], &buf[..]);
+        assert_eq!(5, or_panic!(stream.bytes_available()));
+        let mut buf = vec![];
+        or_panic!(stream.read_to_end(&mut buf));
+        assert_eq!(&b"hello"[..], &buf[..]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn info() {
+        let socket_path = Path::new("//./pipe/infosock");
+
+        let mut listener = or_panic!(PipeListener::bind_with_mode(socket_path, PipeMode::Message));
+        let thread = thread::spawn(move || {
+            let stream = or_panic!(listener.accept());
+            let info = or_panic!(stream.info());
+            assert!(info.is_server_end());
+            assert_eq!(PipeMode::Message, info.mode());
+            assert_eq!(65536, info.out_buffer_size);
+            assert_eq!(PIPE_UNLIMITED_INSTANCES, info.max_instances);
+        });
+
+        let stream = or_panic!(PipeStream::connect(socket_path));
+        let info = or_panic!(stream.info());
+        assert!(!info.is_server_end());
+        assert!(info.is_message_type());
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn client_process_id() {
+        let socket_path = Path::new("//./pipe/clientpidsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let stream = or_panic!(listener.accept());
+            assert_eq!(std::process::id(), or_panic!(stream.client_process_id()));
+        });
+
+        let stream = or_panic!(PipeStream::connect(socket_path));
+        assert_eq!(std::process::id(), or_panic!(stream.server_process_id()));
+        assert!(stream.client_process_id().is_err());
+        assert!(stream.client_computer_name().is_err());
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn overlapped() {
+        let socket_path = Path::new("//./pipe/overlappedsock");
+
+        let mut listener = or_panic!(PipeListener::bind_overlapped(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            assert!(stream.is_overlapped());
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(&b"hello"[..], &buf[..]);
+            or_panic!(stream.write_all(b"world"));
+        });
+
+        let stream = or_panic!(PipeStream::connect_overlapped(socket_path));
+        let mut read = or_panic!(stream.read_overlapped(vec![0; 5]));
+        assert_eq!(None, or_panic!(read.wait_timeout(Duration::from_millis(10))));
+
+        {
+            let mut write = or_panic!(stream.write_overlapped(b"hello".to_vec()));
+            assert_eq!(5, or_panic!(write.wait()));
+        }
+        assert_eq!(5, or_panic!(read.wait()));
+        assert_eq!(&b"world"[..], &read.into_buffer()[..]);
+
+        let mut read = or_panic!(stream.read_overlapped(vec![0; 5]));
+        assert_eq!(0, or_panic!(read.cancel()));
+        drop(read);
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn nonblocking() {
+        let socket_path = Path::new("//./pipe/nonblockingsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let mut stream = or_panic!(PipeStream::connect(socket_path));
+        or_panic!(stream.set_nonblocking(true));
+        let err = stream.read(&mut [0; 5]).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+
+        or_panic!(stream.write_all(b"hello"));
+        or_panic!(stream.set_nonblocking(false));
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(&b"hello"[..], &buf[..]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn disconnect() {
+        let socket_path = Path::new("//./pipe/disconnectsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let mut first = or_panic!(PipeStream::connect(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            // only the accepted instance is left for the second client.
+            drop(listener);
+            for i in 0..2 {
+                if i > 0 {
+                    or_panic!(stream.connect_client());
+                }
+                or_panic!(stream.write_all(b"hello"));
+                or_panic!(stream.flush());
+                or_panic!(stream.disconnect());
+                let err = stream.write(b"hello").unwrap_err();
+                assert_eq!(io::ErrorKind::NotConnected, err.kind());
+            }
+        });
+
+        let mut buf = vec![];
+        or_panic!(first.read_to_end(&mut buf));
+        assert_eq!(&b"hello"[..], &buf[..]);
+
+        let mut second = or_panic!(PipeStream::connect_timeout(socket_path,
+                                                               Duration::from_secs(5)));
+        let mut buf = vec![];
+        or_panic!(second.read_to_end(&mut buf));
+        assert_eq!(&b"hello"[..], &buf[..]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn transact() {
+        let socket_path = Path::new("//./pipe/transactsock");
+
+        let mut listener = or_panic!(PipeListener::bind_with_mode(socket_path, PipeMode::Message));
+        let thread = thread::spawn(move || {
+            let stream = or_panic!(listener.accept());
+            let mut buf = [0; 16];
+            let n = or_panic!(stream.transact(b"ping", &mut buf));
+            assert_eq!(&b"pong"[..], &buf[..n]);
+
+            let mut buf = [0; 2];
+            let err = stream.transact(b"ping", &mut buf).unwrap_err();
+            assert_eq!(Some(ERROR_MORE_DATA as i32), err.raw_os_error());
+        });
+
+        let mut stream = or_panic!(PipeStream::connect(socket_path));
+        for _ in 0..2 {
+            let mut buf = [0; 4];
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(&b"ping"[..], &buf[..]);
+            or_panic!(stream.write_all(b"pong"));
+        }
+        // the client end is in byte read mode.
+        assert!(stream.transact(b"ping", &mut [0; 4]).is_err());
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn call_named_pipe() {
+        let socket_path = Path::new("//./pipe/callsock");
+
+        let mut listener = or_panic!(PipeListener::bind_with_mode(socket_path, PipeMode::Message));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = [0; 16];
+            let n = or_panic!(stream.read(&mut buf));
+            assert_eq!(&b"ping"[..], &buf[..n]);
+            or_panic!(stream.write_all(b"pong"));
+        });
+
+        let mut buf = [0; 16];
+        let n = or_panic!(super::call_named_pipe(socket_path,
+                                                 b"ping",
+                                                 &mut buf,
+                                                 Duration::from_secs(5)));
+        assert_eq!(&b"pong"[..], &buf[..n]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn close() {
+        let socket_path = Path::new("//./pipe/closesock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+            or_panic!(stream.close());
+        });
+
+        let mut stream = or_panic!(PipeStream::connect(socket_path));
+        let mut buf = vec![];
+        or_panic!(stream.read_to_end(&mut buf));
+        assert_eq!(&b"hello"[..], &buf[..]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn is_server() {
+        let socket_path = Path::new("//./pipe/isserversock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            assert!(stream.is_server());
+            assert_eq!(0, or_panic!(stream.read(&mut [0; 4])));
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        assert!(!stream.is_server());
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn max_instances() {
+        let socket_path = Path::new("//./pipe/maxinstancessock");
+
+        assert!(PipeListener::bind_with_max_instances(socket_path, 0).is_err());
+        assert!(PipeListener::bind_with_max_instances(socket_path, 256).is_err());
+
+        let mut listener = or_panic!(PipeListener::bind_with_max_instances(socket_path, 1));
+        let _client = or_panic!(PipeStream::connect(socket_path));
+        let _server = or_panic!(listener.accept());
+
+        let err = PipeStream::connect_timeout(socket_path, Duration::from_millis(50)).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+        let err = listener.accept().unwrap_err();
+        assert_eq!(Some(ERROR_PIPE_BUSY as i32), err.raw_os_error());
+    }
+
+    #[test]
+    fn read_timeout() {
+        let socket_path = Path::new("//./pipe/readtimeoutsock");
+
+        let mut listener = or_panic!(PipeListener::bind_overlapped(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            or_panic!(stream.write_all(b"world"));
+        });
+
+        let mut stream = or_panic!(PipeStream::connect_overlapped(socket_path));
+        let timeout = Some(Duration::from_millis(10));
+        or_panic!(stream.set_read_timeout(timeout));
+        assert_eq!(timeout, or_panic!(stream.read_timeout()));
+        let err = stream.read(&mut [0; 5]).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+
+        // the stream is still usable after a timeout.
+        or_panic!(stream.set_read_timeout(None));
+        or_panic!(stream.write_all(b"hello"));
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(&b"world"[..], &buf[..]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn read_deadline() {
+        let socket_path = Path::new("//./pipe/readdeadlinesock");
+
+        let (tx, rx) = mpsc::channel();
+        let mut listener = or_panic!(PipeListener::bind_overlapped(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            rx.recv().unwrap();
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let stream = or_panic!(PipeStream::connect_overlapped(socket_path));
+        let mut buf = [0; 16];
+        let start = Instant::now();
+        let err = stream.read_deadline(&mut buf, start + Duration::from_millis(100)).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+        assert!(start.elapsed() >= Duration::from_millis(90));
+
+        // a read that gets some data returns it.
+        tx.send(()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert_eq!(5, or_panic!(stream.read_deadline(&mut buf, deadline)));
+        assert_eq!(b"hello", &buf[..5]);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn write_deadline() {
+        let socket_path = Path::new("//./pipe/writedeadlinesock");
+
+        let (tx, rx) = mpsc::channel();
+        let mut listener = or_panic!(PipeListener::bind_overlapped(socket_path));
+        let thread = thread::spawn(move || {
+            // never reads, so the client's writes back up.
+            let _stream = or_panic!(listener.accept());
+            rx.recv().unwrap();
+        });
+
+        let stream = or_panic!(PipeStream::connect_overlapped(socket_path));
+        let buf = vec![0; 1 << 20];
+        let start = Instant::now();
+        match stream.write_deadline(&buf, start + Duration::from_millis(100)) {
+            Ok(n) => assert!(n < buf.len()),
+            Err(err) => assert_eq!(io::ErrorKind::TimedOut, err.kind()),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        assert_eq!(None, or_panic!(stream.write_timeout()));
+
+        tx.send(()).unwrap();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn timeout_requires_overlapped() {
+        let socket_path = Path::new("//./pipe/timeoutoverlappedsock");
+
+        let _listener = or_panic!(PipeListener::bind(socket_path));
+        let stream = or_panic!(PipeStream::connect(socket_path));
+        assert!(stream.set_read_timeout(Some(Duration::from_millis(10))).is_err());
+        assert!(stream.set_write_timeout(Some(Duration::from_secs(0))).is_err());
+        or_panic!(stream.set_write_timeout(None));
+        assert!(stream.write_deadline(b"hello", Instant::now()).is_err());
+        assert!(stream.read_deadline(&mut [0; 5], Instant::now()).is_err());
+    }
+
+    #[test]
+    fn buffered() {
+        let socket_path = Path::new("//./pipe/bufferedsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = BufferedPipeStream::new(or_panic!(listener.accept()));
+            let mut line = String::new();
+            or_panic!(stream.read_line(&mut line));
+            assert_eq!("hello\n", line);
+            or_panic!(stream.write_all(b"one\ntwo\n"));
+        });
+
+        let mut stream = or_panic!(PipeStream::connect(socket_path));
+        or_panic!(stream.write_all(b"hello\n"));
+        let lines = stream.into_buf_reader().lines().collect::<io::Result<Vec<_>>>();
+        assert_eq!(vec!["one", "two"], or_panic!(lines));
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn connect_remote() {
+        let socket_path = Path::new(r"\\.\pipe\connectremotesock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let mut stream = or_panic!(PipeStream::connect_remote(".", "connectremotesock"));
+        let mut buf = vec![];
+        or_panic!(stream.read_to_end(&mut buf));
+        assert_eq!(&b"hello"[..], &buf[..]);
+
+        thread.join().unwrap();
+
+        for &(server, pipe) in &[("", "a"), (".", ""), (r"a\b", "c"), (".", "a/b"), (".", "a\0")] {
+            let err = PipeStream::connect_remote(server, pipe).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let socket_path = Path::new("//./pipe/buildersock");
+
+        let mut listener = or_panic!(PipeListenerBuilder::new()
+            .mode(PipeMode::Message)
+            .in_buffer_size(1024)
+            .out_buffer_size(2048)
+            .max_instances(2)
+            .bind(socket_path));
+        let thread = thread::spawn(move || for stream in listener.incoming().take(2) {
+            let stream = or_panic!(stream);
+            let info = or_panic!(stream.info());
+            assert_eq!(PipeMode::Message, info.mode());
+            assert_eq!(2048, info.out_buffer_size);
+            assert_eq!(2, info.max_instances);
+        });
+
+        for _ in 0..2 {
+            drop(or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5))));
+        }
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn interior_nul() {
+        let socket_path = Path::new("//./pipe/interior\0nulsock");
+
+        let err = PipeListener::bind(socket_path).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let err = PipeStream::connect(socket_path).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let err = PipeStream::connect_timeout(socket_path, Duration::from_millis(10)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn eof_after_client_close() {
+        let socket_path = Path::new("//./pipe/eofsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = vec![];
+            or_panic!(stream.read_to_end(&mut buf));
+            assert_eq!(&b"hello"[..], &buf[..]);
+            assert_eq!(0, or_panic!(stream.read(&mut [0; 4])));
+        });
+
+        let mut stream = or_panic!(PipeStream::connect(socket_path));
+        or_panic!(stream.write_all(b"hello"));
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn accept_one() {
+        let socket_path = Path::new("//./pipe/acceptonesock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || for _ in 0..2 {
+            let mut stream = or_panic!(listener.accept_one());
+            or_panic!(stream.write_all(b"hello"));
+            assert_eq!(0, or_panic!(stream.read(&mut [0; 4])));
+        });
+
+        for _ in 0..2 {
+            let mut stream =
+                or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+            // the only instance is connected to this client.
+            let err = PipeStream::connect(socket_path).unwrap_err();
+            assert_eq!(Some(ERROR_PIPE_BUSY as i32), err.raw_os_error());
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(b"hello", &buf);
+        }
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn write_through() {
+        const FILE_WRITE_THROUGH: ULONG = 0x2;
+        let socket_path = Path::new("//./pipe/writethroughsock");
+
+        let mut listener = or_panic!(PipeListenerBuilder::new()
+            .write_through(true)
+            .bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mode = or_panic!(file_mode(stream.as_raw_handle()));
+            assert!(mode & FILE_WRITE_THROUGH != 0, "server mode {:#x}", mode);
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let mut stream = or_panic!(PipeStreamBuilder::new()
+            .write_through(true)
+            .connect_timeout(socket_path, Duration::from_secs(5)));
+        let mode = or_panic!(file_mode(stream.as_raw_handle()));
+        assert!(mode & FILE_WRITE_THROUGH != 0, "client mode {:#x}", mode);
+        let (server, client) = or_panic!(PipeStream::pair());
+        assert_eq!(0, or_panic!(file_mode(server.as_raw_handle())) & FILE_WRITE_THROUGH);
+        assert_eq!(0, or_panic!(file_mode(client.as_raw_handle())) & FILE_WRITE_THROUGH);
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn connect_with_share() {
+        let socket_path = Path::new("//./pipe/connectwithsharesock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let mut stream = or_panic!(PipeStream::connect_with_share(socket_path,
+                                                                  FILE_SHARE_READ |
+                                                                  FILE_SHARE_WRITE));
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn shared_ref() {
+        let socket_path = Path::new("//./pipe/sharedrefsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let stream = Arc::new(or_panic!(listener.accept()));
+            let writer = stream.clone();
+            let thread = thread::spawn(move || or_panic!((&*writer).write_all(b"hello")));
+            let mut buf = [0; 5];
+            or_panic!((&*stream).read_exact(&mut buf));
+            assert_eq!(b"world", &buf);
+            thread.join().unwrap();
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut buf = [0; 5];
+        or_panic!((&stream).read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+        or_panic!((&stream).write_all(b"world"));
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn write_vectored() {
+        let socket_path = Path::new("//./pipe/writevectoredsock");
+
+        let mut listener = or_panic!(PipeListener::bind_with_mode(socket_path, PipeMode::Message));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let bufs = [IoSlice::new(b"hel"), IoSlice::new(b""), IoSlice::new(b"lo")];
+            assert_eq!(5, or_panic!(stream.write_vectored(&bufs)));
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut buf = [0; 16];
+        // the slices arrive in order as a single message.
+        assert_eq!(5, or_panic!(stream.read(&mut buf)));
+        assert_eq!(b"hello", &buf[..5]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn read_vectored() {
+        let socket_path = Path::new("//./pipe/readvectoredsock");
+
+        let mut listener = or_panic!(PipeListener::bind_with_mode(socket_path, PipeMode::Message));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut first = [0; 3];
+            let mut second = [0; 8];
+            let n = {
+                let mut bufs = [IoSliceMut::new(&mut first), IoSliceMut::new(&mut second)];
+                or_panic!(stream.read_vectored(&mut bufs))
+            };
+            assert_eq!(5, n);
+            assert_eq!(b"hel", &first);
+            assert_eq!(b"lo", &second[..2]);
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        or_panic!(stream.write_all(b"hello"));
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn set_read_mode() {
+        let socket_path = Path::new("//./pipe/setreadmodesock");
+
+        let mut listener = or_panic!(PipeListener::bind_with_mode(socket_path, PipeMode::Message));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+            or_panic!(stream.write_all(b"world"));
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        assert_eq!(PipeMode::Byte, or_panic!(stream.read_mode()));
+        or_panic!(stream.set_read_mode(PipeMode::Message));
+        assert_eq!(PipeMode::Message, or_panic!(stream.read_mode()));
+        let mut buf = [0; 16];
+        assert_eq!(5, or_panic!(stream.read(&mut buf)));
+        assert_eq!(b"hello", &buf[..5]);
+        assert_eq!(5, or_panic!(stream.read(&mut buf)));
+        assert_eq!(b"world", &buf[..5]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn set_read_mode_byte_pipe() {
+        let socket_path = Path::new("//./pipe/setreadmodebytesock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            // keep the pipe connected until the client is done.
+            assert_eq!(0, or_panic!(stream.read(&mut [0; 4])));
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let err = stream.set_read_mode(PipeMode::Message).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        or_panic!(stream.set_read_mode(PipeMode::Byte));
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn from_raw_handle_server() {
+        let socket_path = Path::new("//./pipe/fromrawhandleserversock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let handle = or_panic!(listener.accept()).into_raw_handle();
+            let mut stream = unsafe { PipeStream::from_raw_handle_server(handle) };
+            assert!(stream.is_server());
+            or_panic!(stream.client_process_id());
+            or_panic!(stream.write_all(b"hello"));
+            or_panic!(stream.close());
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut buf = vec![];
+        or_panic!(stream.read_to_end(&mut buf));
+        assert_eq!(&b"hello"[..], &buf[..]);
+        // the server disconnected the pipe rather than just closing its end.
+        let err = stream.write(b"x").unwrap_err();
+        assert_eq!(Some(ERROR_PIPE_NOT_CONNECTED as i32), err.raw_os_error());
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn try_from_raw_handle() {
+        let socket_path = Path::new("//./pipe/tryfromrawhandlesock");
+
+        let file = or_panic!(std::fs::File::open("Cargo.toml"));
+        let handle = file.into_raw_handle();
+        let err = unsafe { PipeStream::try_from_raw_handle(handle) }.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        drop(unsafe { std::fs::File::from_raw_handle(handle) });
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let handle = or_panic!(listener.accept()).into_raw_handle();
+            let mut stream = or_panic!(unsafe { PipeStream::try_from_raw_handle(handle) });
+            assert!(stream.is_server());
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let handle =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)))
+                .into_raw_handle();
+        let mut stream = or_panic!(unsafe { PipeStream::try_from_raw_handle(handle) });
+        assert!(!stream.is_server());
+        let mut buf = vec![];
+        or_panic!(stream.read_to_end(&mut buf));
+        assert_eq!(&b"hello"[..], &buf[..]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn register_iocp() {
+        let socket_path = Path::new("//./pipe/registeriocpsock");
+
+        let port = unsafe {
+            CreateIoCompletionPort(INVALID_HANDLE_VALUE, std::ptr::null_mut(), 0, 1)
+        };
+        assert!(!port.is_null());
+        let port = Handle { inner: port };
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+            or_panic!(stream.write_all(b"world"));
+            let mut buf = [0; 1];
+            let _ = stream.read(&mut buf);
+        });
+
+        let stream = or_panic!(PipeStreamBuilder::new()
+            .overlapped(true)
+            .connect_timeout(socket_path, Duration::from_secs(5)));
+        or_panic!(stream.register_iocp(port.inner, 7));
+
+        let mut buf = [0; 5];
+        let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+        let ok = unsafe {
+            ReadFile(stream.as_raw_handle(),
+                     buf.as_mut_ptr() as LPVOID,
+                     buf.len() as DWORD,
+                     std::ptr::null_mut(),
+                     &mut overlapped)
+        };
+        if ok == 0 {
+            let err = io::Error::last_os_error();
+            assert_eq!(Some(ERROR_IO_PENDING as i32), err.raw_os_error());
+        }
+
+        let mut bytes = 0;
+        let mut key = 0;
+        let mut completed = std::ptr::null_mut();
+        let ok = unsafe {
+            GetQueuedCompletionStatus(port.inner, &mut bytes, &mut key, &mut completed, 5000)
+        };
+        assert!(ok != 0);
+        assert_eq!(7, key);
+        assert_eq!(5, bytes);
+        assert_eq!(&mut overlapped as LPOVERLAPPED, completed);
+        assert_eq!(b"hello", &buf);
+
+        // the stream's own reads don't post to the port.
+        or_panic!((&stream).read_exact(&mut buf));
+        assert_eq!(b"world", &buf);
+        let ok = unsafe {
+            GetQueuedCompletionStatus(port.inner, &mut bytes, &mut key, &mut completed, 0)
+        };
+        assert_eq!(0, ok);
+        drop(stream);
+
+        thread.join().unwrap();
+
+        let plain_path = Path::new("//./pipe/registeriocpplainsock");
+        let _listener = or_panic!(PipeListener::bind(plain_path));
+        let stream = or_panic!(PipeStream::connect(plain_path));
+        let err = stream.register_iocp(port.inner, 0).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn read_to_end() {
+        let socket_path = Path::new("//./pipe/readtoendsock");
+
+        let data: Vec<u8> = (0..200000).map(|i| i as u8).collect();
+        let expected = data.clone();
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(&data[..100000]));
+            // the rest arrives after the reader has caught up.
+            thread::sleep(Duration::from_millis(50));
+            or_panic!(stream.write_all(&data[100000..]));
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut buf = b"start".to_vec();
+        assert_eq!(200000, or_panic!(stream.read_to_end(&mut buf)));
+        assert_eq!(b"start", &buf[..5]);
+        assert!(expected == buf[5..]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn stats() {
+        let socket_path = Path::new("//./pipe/statssock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+            or_panic!(stream.write_vectored(&[IoSlice::new(b"wor"), IoSlice::new(b"ld")]));
+            let mut buf = [0; 3];
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(PipeStats { bytes_read: 3, bytes_written: 10 }, stream.stats());
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        assert_eq!(PipeStats::default(), stream.stats());
+        let mut first = [0; 4];
+        let mut second = [0; 6];
+        or_panic!(stream.read_exact(&mut first));
+        let n = or_panic!(stream.read_vectored(&mut [IoSliceMut::new(&mut second)]));
+        let clone = or_panic!(stream.try_clone());
+        or_panic!((&clone).write_all(b"bye"));
+        assert_eq!(PipeStats { bytes_read: 4 + n as u64, bytes_written: 3 }, stream.stats());
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn seek() {
+        let socket_path = Path::new("//./pipe/seeksock");
+
+        let _listener = or_panic!(PipeListener::bind(socket_path));
+        let mut stream = or_panic!(PipeStream::connect(socket_path));
+        assert_eq!(0, or_panic!(stream.stream_position()));
+        let err = stream.seek(SeekFrom::Start(0)).unwrap_err();
+        assert_eq!(io::ErrorKind::Unsupported, err.kind());
+        let err = stream.seek(SeekFrom::Current(1)).unwrap_err();
+        assert_eq!(io::ErrorKind::Unsupported, err.kind());
+    }
+
+    #[test]
+    fn drain_timeout() {
+        let socket_path = Path::new("//./pipe/draintimeoutsock");
+
+        let (tx, rx) = mpsc::channel();
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+            let err = stream.drain_timeout(Duration::from_millis(50)).unwrap_err();
+            assert_eq!(io::ErrorKind::TimedOut, err.kind());
+            tx.send(()).unwrap();
+            or_panic!(stream.drain_timeout(Duration::from_secs(5)));
+            or_panic!(stream.drain());
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        rx.recv().unwrap();
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn pipe_error() {
+        let socket_path = Path::new("//./pipe/pipeerrorsock");
+
+        let err = PipeStream::connect(socket_path).map_err(PipeError::from).unwrap_err();
+        assert!(matches!(err, PipeError::NotFound));
+        let err = io::Error::from(err);
+        assert_eq!(Some(ERROR_FILE_NOT_FOUND as i32), err.raw_os_error());
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.disconnect());
+            let err = PipeError::from(stream.write(b"x").unwrap_err());
+            assert!(matches!(err, PipeError::NotConnected));
+        });
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        thread.join().unwrap();
+        drop(stream);
+
+        let err = PipeError::from(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        assert!(matches!(err, PipeError::Timeout));
+        let err = PipeError::from(io::Error::from_raw_os_error(ERROR_ACCESS_DENIED as i32));
+        assert!(matches!(err, PipeError::Other(_)));
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn set_inheritable() {
+        let socket_path = Path::new("//./pipe/setinheritablesock");
+
+        fn inheritable(stream: &PipeStream) -> bool {
+            let mut flags = 0;
+            assert!(unsafe { GetHandleInformation(stream.as_raw_handle(), &mut flags) } != 0);
+            flags & HANDLE_FLAG_INHERIT != 0
+        }
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let stream = or_panic!(PipeStream::connect(socket_path));
+        let server = or_panic!(listener.accept());
+        assert!(!inheritable(&stream));
+        assert!(!inheritable(&server));
+
+        or_panic!(stream.set_inheritable(true));
+        assert!(inheritable(&stream));
+        assert!(!inheritable(&or_panic!(stream.try_clone())));
+        or_panic!(stream.set_inheritable(false));
+        assert!(!inheritable(&stream));
+    }
+
+    #[test]
+    fn pair() {
+        let (mut server, mut client) = or_panic!(PipeStream::pair());
+        assert!(server.is_server());
+        assert!(!client.is_server());
+
+        let thread = thread::spawn(move || {
+            or_panic!(server.write_all(b"hello"));
+            let mut buf = [0; 5];
+            or_panic!(server.read_exact(&mut buf));
+            assert_eq!(b"world", &buf);
+        });
+
+        let mut buf = [0; 5];
+        or_panic!(client.read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+        or_panic!(client.write_all(b"world"));
+
+        thread.join().unwrap();
+
+        // every pair gets its own pipe.
+        let _first = or_panic!(PipeStream::pair());
+        let _second = or_panic!(PipeStream::pair());
+    }
+
+    #[test]
+    fn readiness_event() {
+        let socket_path = Path::new("//./pipe/readinesseventsock");
+
+        let (tx, rx) = mpsc::channel();
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            rx.recv().unwrap();
+            or_panic!(stream.write_all(b"hello"));
+            rx.recv().unwrap();
+        });
+
+        let stream = or_panic!(PipeStreamBuilder::new()
+            .overlapped(true)
+            .connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut readiness = or_panic!(stream.readiness_event());
+        let event = readiness.as_raw_handle();
+        assert!(!readiness.is_ready());
+        assert_eq!(unsafe { WaitForSingleObject(event, 0) }, WAIT_TIMEOUT);
+
+        tx.send(()).unwrap();
+        assert_eq!(unsafe { WaitForSingleObject(event, 5000) }, WAIT_OBJECT_0);
+        assert!(readiness.is_ready());
+        let mut buf = [0; 5];
+        or_panic!((&stream).read_exact(&mut buf));
+        assert_eq!(&buf, b"hello");
+
+        or_panic!(readiness.rearm());
+        assert!(!readiness.is_ready());
+        assert_eq!(readiness.as_raw_handle(), event);
+
+        // the server going away makes the stream readable too.
+        tx.send(()).unwrap();
+        assert_eq!(unsafe { WaitForSingleObject(event, 5000) }, WAIT_OBJECT_0);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn wait_readable_method() {
+        let socket_path = Path::new("//./pipe/waitreadablemethodsock");
+        let overlapped_path = Path::new("//./pipe/waitreadablemethodoverlappedsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let mut overlapped_listener = or_panic!(PipeListener::bind(overlapped_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut overlapped = or_panic!(overlapped_listener.accept());
+            thread::sleep(Duration::from_millis(100));
+            or_panic!(stream.write_all(b"hello"));
+            or_panic!(overlapped.write_all(b"hello"));
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let overlapped = or_panic!(PipeStreamBuilder::new()
+            .overlapped(true)
+            .connect_timeout(overlapped_path, Duration::from_secs(5)));
+        for stream in [&stream, &overlapped] {
+            assert!(!or_panic!(stream.wait_readable(Some(Duration::from_millis(10)))));
+        }
+        for stream in [&stream, &overlapped] {
+            assert!(or_panic!(stream.wait_readable(Some(Duration::from_secs(5)))));
+            // nothing was read.
+            assert_eq!(5, or_panic!(stream.bytes_available()));
+            let mut buf = [0; 5];
+            or_panic!((&*stream).read_exact(&mut buf));
+        }
+
+        thread.join().unwrap();
+        // the server has gone, so a read wouldn't block.
+        for stream in [&stream, &overlapped] {
+            assert!(or_panic!(stream.wait_readable(None)));
+        }
+    }
+
+    #[test]
+    fn copy_to() {
+        let socket_path = Path::new("//./pipe/copytosock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            for _ in 0..100 {
+                or_panic!(stream.write_all(&[7; 1000]));
+            }
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut data = Vec::new();
+        assert_eq!(or_panic!(stream.copy_to(&mut data)), 100000);
+        assert_eq!(data, vec![7; 100000]);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn into_buf_writer() {
+        let socket_path = Path::new("//./pipe/intobufwritersock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut writer = or_panic!(listener.accept()).into_buf_writer();
+            or_panic!(writer.write_all(b"hello"));
+            // waits for the client to read the data.
+            or_panic!(writer.flush());
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn framed_pipe() {
+        let socket_path = Path::new("//./pipe/framedpipesock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            // one frame split across writes, then two frames in one write.
+            or_panic!(stream.write_all(&[5, 0]));
+            or_panic!(stream.write_all(&[0, 0, b'h', b'e']));
+            or_panic!(stream.write_all(b"llo"));
+            or_panic!(stream.write_all(&[0, 0, 0, 0, 2, 0, 0, 0, b'h', b'i']));
+            let mut framed = FramedPipe::with_length_prefix(stream, LengthPrefix::U16Be);
+            or_panic!(framed.send_frame(b"big endian"));
+            assert_eq!(framed.send_frame(&[0; 65536]).unwrap_err().kind(),
+                       io::ErrorKind::InvalidInput);
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut framed = FramedPipe::new(stream);
+        assert_eq!(or_panic!(framed.recv_frame()), b"hello");
+        assert_eq!(or_panic!(framed.recv_frame()), b"");
+        assert_eq!(or_panic!(framed.recv_frame()), b"hi");
+        let mut framed =
+            FramedPipe::with_length_prefix(framed.into_inner(), LengthPrefix::U16Be);
+        assert_eq!(or_panic!(framed.recv_frame()), b"big endian");
+        thread.join().unwrap();
+        assert_eq!(framed.recv_frame().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn max_frame_size() {
+        let socket_path = Path::new("//./pipe/maxframesizesock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(&[0xff, 0xff, 0xff, 0xff]));
+            let mut framed = FramedPipe::new(stream);
+            or_panic!(framed.send_frame(&[0; 100]));
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut framed = FramedPipe::new(stream);
+        assert_eq!(framed.max_frame_size(), 16 * 1024 * 1024);
+        assert_eq!(framed.recv_frame().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // the oversized frame's length was consumed, so the next frame is intact.
+        framed.set_max_frame_size(100);
+        assert_eq!(or_panic!(framed.recv_frame()).len(), 100);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn buffered_pipe_writer() {
+        let socket_path = Path::new("//./pipe/bufferedpipewritersock");
+
+        let (to_client, from_server) = mpsc::channel();
+        let (to_server, from_client) = mpsc::channel();
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut writer = BufferedPipeWriter::new(or_panic!(listener.accept()));
+            or_panic!(writer.write_all(b"hello"));
+            assert_eq!(b"hello", writer.buffer());
+            to_client.send(()).unwrap();
+            from_client.recv().unwrap();
+
+            // returns while the client still hasn't read anything.
+            or_panic!(writer.flush());
+            assert!(writer.buffer().is_empty());
+            to_client.send(()).unwrap();
+
+            or_panic!(writer.write_all(b"world"));
+            or_panic!(writer.sync());
+            let stream = or_panic!(writer.into_inner());
+            assert_eq!(0, or_panic!(stream.bytes_available()));
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        from_server.recv().unwrap();
+        assert_eq!(0, or_panic!(stream.bytes_available()));
+        to_server.send(()).unwrap();
+        from_server.recv().unwrap();
+        assert_eq!(5, or_panic!(stream.bytes_available()));
+        let mut buf = [0; 10];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"helloworld", &buf);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn is_retryable() {
+        for &code in &[ERROR_FILE_NOT_FOUND, ERROR_PIPE_BUSY, ERROR_ACCESS_DENIED] {
+            assert!(super::is_retryable(&io::Error::from_raw_os_error(code as i32)));
+        }
+        assert!(!super::is_retryable(&io::Error::from_raw_os_error(ERROR_BROKEN_PIPE as i32)));
+        let err = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        assert!(!super::is_retryable(&err));
+
+        let err = PipeStream::connect("//./pipe/isretryablesock").unwrap_err();
+        assert!(super::is_retryable(&err));
+
+        // an access mismatch keeps the raw OS error.
+        let socket_path = Path::new("//./pipe/isretryableaccesssock");
+        let _listener = or_panic!(PipeListener::bind_with_access(socket_path,
+                                                                 PipeAccess::Outbound));
+        let err = PipeStream::connect(socket_path).unwrap_err();
+        assert_eq!(Some(ERROR_ACCESS_DENIED as i32), err.raw_os_error());
+        assert!(super::is_retryable(&err));
+    }
+
+    #[test]
+    fn display_error() {
+        assert_eq!(Some("ERROR_PIPE_BUSY"), error_name(ERROR_PIPE_BUSY));
+        assert_eq!(Some("ERROR_NO_DATA"), error_name(232));
+        assert_eq!(None, error_name(12345));
+
+        let err = io::Error::from_raw_os_error(ERROR_PIPE_BUSY as i32);
+        let shown = super::display_error(&err).to_string();
+        assert!(shown.starts_with("ERROR_PIPE_BUSY (231): "), "{}", shown);
+        assert!(shown.ends_with(&err.to_string()), "{}", shown);
+        assert_eq!(Some(ERROR_PIPE_BUSY as i32), err.raw_os_error());
+
+        let err = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+        assert_eq!("timed out", super::display_error(&err).to_string());
+    }
+
+    #[test]
+    fn optimize_for_remote() {
+        let socket_path = Path::new("//./pipe/optimizeforremotesock");
+
+        let mut listener = or_panic!(PipeListener::bind_with_mode(socket_path, PipeMode::Message));
+        let stream = or_panic!(PipeStream::connect(socket_path));
+        or_panic!(stream.set_read_mode(PipeMode::Message));
+        let server = or_panic!(listener.accept());
+
+        // the server is local, so there's nothing to tune.
+        or_panic!(stream.optimize_for_remote());
+        assert_eq!(PipeMode::Message, or_panic!(stream.read_mode()));
+        let err = server.optimize_for_remote().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn reconnect() {
+        let socket_path = Path::new("//./pipe/reconnectsock");
+
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            for message in [&b"one"[..], &b"two"[..]] {
+                let mut listener = or_panic!(PipeListener::bind_with_mode(socket_path,
+                                                                          PipeMode::Message));
+                tx.send(()).unwrap();
+                let mut stream = or_panic!(listener.accept());
+                or_panic!(stream.write_all(message));
+            }
+        });
+
+        rx.recv().unwrap();
+        let mut stream = or_panic!(PipeStreamBuilder::new()
+            .overlapped(true)
+            .connect_timeout(socket_path, Duration::from_secs(5)));
+        or_panic!(stream.set_read_mode(PipeMode::Message));
+        or_panic!(stream.set_read_timeout(Some(Duration::from_secs(5))));
+        let mut buf = vec![];
+        or_panic!(stream.read_to_end(&mut buf));
+        assert_eq!(&b"one"[..], &buf[..]);
+
+        // the server has restarted.
+        rx.recv().unwrap();
+        or_panic!(stream.reconnect());
+        assert_eq!(PipeMode::Message, or_panic!(stream.read_mode()));
+        assert_eq!(Some(Duration::from_secs(5)), or_panic!(stream.read_timeout()));
+        assert!(stream.is_overlapped());
+        buf.clear();
+        or_panic!(stream.read_to_end(&mut buf));
+        assert_eq!(&b"two"[..], &buf[..]);
+
+        thread.join().unwrap();
+
+        let (mut server, _client) = or_panic!(PipeStream::pair());
+        let err = server.reconnect().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn reconnect_keeps_mode() {
+        let socket_path = Path::new("//./pipe/reconnectmodesock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let _first = or_panic!(listener.accept());
+            let _second = or_panic!(listener.accept());
+            rx.recv().unwrap();
+        });
+
+        let mut stream = or_panic!(PipeStream::connect_timeout(socket_path,
+                                                               Duration::from_secs(5)));
+        or_panic!(stream.set_nonblocking(true));
+        stream.set_lazy_flush(true);
+        // nothing reads this, so closing the old connection mustn't wait.
+        or_panic!(stream.write_all(b"unread"));
+        or_panic!(stream.reconnect());
+        assert!(stream.is_nonblocking());
+        assert!(stream.shared.lazy_flush.load(Ordering::Relaxed));
+        let err = stream.read(&mut [0; 8]).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+
+        tx.send(()).unwrap();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn is_connected() {
+        let socket_path = Path::new("//./pipe/isconnectedsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let client = or_panic!(PipeStream::connect(socket_path));
+        let mut server = or_panic!(listener.accept());
+        or_panic!(server.write_all(b"hello"));
+        assert!(client.is_connected());
+        assert!(server.is_connected());
+        // the peek left the data in the pipe.
+        assert_eq!(5, or_panic!(client.bytes_available()));
+
+        let mut buf = [0; 5];
+        or_panic!((&client).read_exact(&mut buf));
+        drop(client);
+        assert!(!server.is_connected());
+        or_panic!(server.disconnect());
+        assert!(!server.is_connected());
+    }
+
+    #[test]
+    fn cancel_io() {
+        let socket_path = Path::new("//./pipe/canceliosock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let server = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            assert_eq!(0, or_panic!(stream.read(&mut [0; 4])));
+        });
+
+        let stream =
+            Arc::new(or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5))));
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let stream = stream.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let err = (&*stream).read(&mut [0; 4]).unwrap_err();
+                done.store(true, Ordering::SeqCst);
+                assert_eq!(Some(ERROR_OPERATION_ABORTED as i32), err.raw_os_error());
+            })
+        };
+
+        // the read may not have started yet, so keep cancelling until it stops.
+        while !done.load(Ordering::SeqCst) {
+            or_panic!(stream.cancel_io());
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        reader.join().unwrap();
+        drop(stream);
+        server.join().unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_stream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use async_pipe::AsyncPipeStream;
+
+        let socket_path = Path::new("//./pipe/asyncstreamsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            or_panic!(stream.write_all(&buf));
+        });
+
+        let rt = or_panic!(tokio::runtime::Builder::new_current_thread().build());
+        let connect = AsyncPipeStream::connect(socket_path, Duration::from_secs(5));
+        let mut stream = or_panic!(rt.block_on(connect));
+        or_panic!(rt.block_on(stream.write_all(b"hello")));
+        or_panic!(rt.block_on(stream.flush()));
+        let mut buf = [0; 5];
+        or_panic!(rt.block_on(stream.read_exact(&mut buf)));
+        assert_eq!(b"hello", &buf);
+
+        thread.join().unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_drop_with_pending_read() {
+        use std::future::poll_fn;
+        use std::pin::Pin;
+        use std::task::Poll;
+        use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+        use async_pipe::AsyncPipeStream;
+
+        let socket_path = Path::new("//./pipe/asyncdroppendingreadsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = vec![];
+            or_panic!(stream.read_to_end(&mut buf));
+            assert_eq!(b"hello", &buf[..]);
+        });
+
+        let rt = or_panic!(tokio::runtime::Builder::new_current_thread().build());
+        let connect = AsyncPipeStream::connect(socket_path, Duration::from_secs(5));
+        let mut stream = or_panic!(rt.block_on(connect));
+        // start a read that waits for data which never comes.
+        rt.block_on(poll_fn(|cx| {
+            let mut buf = [0; 5];
+            let mut buf = ReadBuf::new(&mut buf);
+            assert!(Pin::new(&mut stream).poll_read(cx, &mut buf).is_pending());
+            Poll::Ready(())
+        }));
+        // dropping the stream without a flush cancels the read but not the
+        // write.
+        or_panic!(rt.block_on(stream.write_all(b"hello")));
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_listener() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use async_pipe::AsyncPipeListener;
+
+        let socket_path = Path::new("//./pipe/asynclistenersock");
+
+        let mut listener = or_panic!(AsyncPipeListener::bind(socket_path));
+        let thread = thread::spawn(move || for _ in 0..2 {
+            let mut stream =
+                or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(b"hello", &buf);
+        });
+
+        let rt = or_panic!(tokio::runtime::Builder::new_current_thread().build());
+        for _ in 0..2 {
+            let mut stream = or_panic!(rt.block_on(listener.accept()));
+            or_panic!(rt.block_on(stream.write_all(b"hello")));
+            or_panic!(rt.block_on(stream.flush()));
+            // wait for the client to go away before accepting the next one.
+            assert_eq!(0, or_panic!(rt.block_on(stream.read(&mut [0; 4]))));
+        }
+
+        thread.join().unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_listener_drop() {
+        use std::future::{poll_fn, Future};
+        use std::pin::Pin;
+        use std::task::Poll;
+        use async_pipe::AsyncPipeListener;
+
+        let socket_path = Path::new("//./pipe/asynclistenerdropsock");
+
+        let rt = or_panic!(tokio::runtime::Builder::new_current_thread().build());
+        let mut listener = or_panic!(AsyncPipeListener::bind(socket_path));
+        rt.block_on(poll_fn(|cx| {
+            assert!(Pin::new(&mut listener.accept()).poll(cx).is_pending());
+            Poll::Ready(())
+        }));
+        {
+            let _runtime = rt.enter();
+            drop(listener);
+        }
+
+        // once the accept is woken the pipe is closed and can be bound again.
+        let start = Instant::now();
+        loop {
+            match PipeListener::bind(socket_path) {
+                Ok(_) => break,
+                Err(ref err) if start.elapsed() < Duration::from_secs(5) => {
+                    assert_eq!(io::ErrorKind::AddrInUse, err.kind());
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) => panic!("listener still bound: {}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn stdio() {
+        use std::process::{Command, Stdio};
+
+        let socket_path = Path::new("//./pipe/stdiosock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = vec![];
+            or_panic!(stream.read_to_end(&mut buf));
+            assert_eq!(&b"hello\r\n"[..], &buf[..]);
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let status = or_panic!(Command::new("cmd.exe")
+            .args(["/C", "echo hello"])
+            .stdout(Stdio::from(stream))
+            .status());
+        assert!(status.success());
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn read_message() {
+        let socket_path = Path::new("//./pipe/readmessagesock");
+
+        let mut listener = or_panic!(PipeListener::bind_with_mode(socket_path, PipeMode::Message));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"0123456789"));
+            assert_eq!(0, or_panic!(stream.read(&mut [0; 4])));
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        or_panic!(stream.set_read_mode(PipeMode::Message));
+        let mut buf = [0; 4];
+        assert_eq!((4, true), or_panic!(stream.read_message(&mut buf)));
+        assert_eq!(b"0123", &buf);
+        assert_eq!(6, or_panic!(stream.message_bytes_left()));
+        let mut buf = [0; 16];
+        assert_eq!((6, false), or_panic!(stream.read_message(&mut buf)));
+        assert_eq!(b"456789", &buf[..6]);
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn wait_connect() {
+        let socket_path = Path::new("//./pipe/waitconnectsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            or_panic!(listener.wait_connect());
+            // the client is already connected.
+            or_panic!(listener.wait_connect());
+            rx.recv().unwrap();
+
+            // accept returns the client that was waited for, not a later one.
+            let mut first = or_panic!(listener.accept());
+            let mut buf = [0; 5];
+            or_panic!(first.read_exact(&mut buf));
+            assert_eq!(b"first", &buf);
+            let mut second = or_panic!(listener.accept());
+            or_panic!(second.read_exact(&mut buf));
+            assert_eq!(b"later", &buf);
+        });
+
+        let mut first =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        or_panic!(first.write_all(b"first"));
+        tx.send(()).unwrap();
+        let mut second =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        or_panic!(second.write_all(b"later"));
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn accept_timeout() {
+        let socket_path = Path::new("//./pipe/accepttimeoutsock");
+
+        let mut listener =
+            or_panic!(PipeListenerBuilder::new().overlapped(true).bind(socket_path));
+        assert!(or_panic!(listener.accept_timeout(Duration::from_millis(50))).is_none());
+
+        // the instance that timed out still takes clients.
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept_timeout(Duration::from_secs(5)))
+                .expect("a client connected");
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+
+        thread.join().unwrap();
+
+        let plain_path = Path::new("//./pipe/accepttimeoutplainsock");
+        let mut listener = or_panic!(PipeListener::bind(plain_path));
+        let err = listener.accept_timeout(Duration::from_millis(50)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn shutdown_handle() {
+        let socket_path = Path::new("//./pipe/shutdownhandlesock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let shutdown = listener.shutdown_handle();
+        let thread = thread::spawn(move || {
+            let err = listener.accept().unwrap_err();
+            assert_eq!(io::ErrorKind::ConnectionAborted, err.kind());
+            let err = listener.accept().unwrap_err();
+            assert_eq!(io::ErrorKind::ConnectionAborted, err.kind());
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(!shutdown.is_signaled());
+        shutdown.signal();
+        assert!(shutdown.is_signaled());
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn reject_remote_clients() {
+        let socket_path = Path::new("//./pipe/rejectremotesock");
+
+        let mut listener = or_panic!(PipeListenerBuilder::new()
+            .reject_remote_clients(true)
+            .backlog(2)
+            .bind(socket_path));
+        let thread = thread::spawn(move || for _ in 0..2 {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        // local clients can still connect, to the spares too.
+        for _ in 0..2 {
+            let mut stream =
+                or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(b"hello", &buf);
+        }
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn non_exclusive() {
+        let socket_path = Path::new("//./pipe/nonexclusivesock");
+
+        let servers: Vec<_> = (0..2)
+            .map(|_| {
+                let mut listener = or_panic!(PipeListenerBuilder::new()
+                    .exclusive(false)
+                    .bind(socket_path));
+                thread::spawn(move || {
+                    let mut stream = or_panic!(listener.accept_one());
+                    or_panic!(stream.write_all(b"hello"));
+                })
+            })
+            .collect();
+
+        // each listener's instance takes one of the clients.
+        for _ in 0..2 {
+            let mut stream =
+                or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(b"hello", &buf);
+        }
+
+        for server in servers {
+            server.join().unwrap();
+        }
+
+        // a real denial isn't mistaken for the name being taken.
+        let socket_path = Path::new("//./pipe/nonexclusivedeniedsock");
+        let _listener = or_panic!(PipeListener::bind_with_access(socket_path,
+                                                                 PipeAccess::Inbound));
+        let err = PipeListenerBuilder::new()
+            .access(PipeAccess::Outbound)
+            .exclusive(false)
+            .bind(socket_path)
+            .unwrap_err();
+        assert_eq!(Some(ERROR_ACCESS_DENIED as i32), err.raw_os_error());
+    }
+
+    #[test]
+    fn accept_after_failed_connect() {
+        let socket_path = Path::new("//./pipe/acceptafterfailedconnectsock");
+
+        let mut listener =
+            or_panic!(PipeListenerBuilder::new().max_instances(1).bind(socket_path));
+        // a client that is gone before the accept makes the connect fail.
+        drop(or_panic!(PipeStream::connect(socket_path)));
+        let err = listener.accept().unwrap_err();
+        assert_eq!(Some(ERROR_NO_DATA as i32), err.raw_os_error());
+
+        // the only instance allowed was kept for the next client.
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        assert_eq!(1, or_panic!(stream.current_instances()));
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn into_incoming() {
+        let socket_path = Path::new("//./pipe/intoincomingsock");
+
+        let listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || for stream in listener.into_incoming().take(2) {
+            let mut stream = or_panic!(stream);
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        for _ in 0..2 {
+            let mut stream =
+                or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(b"hello", &buf);
+        }
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn incoming_with_pid() {
+        let socket_path = Path::new("//./pipe/incomingwithpidsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            for result in listener.incoming_with_pid().take(2) {
+                let (mut stream, pid) = or_panic!(result);
+                assert_eq!(Some(std::process::id()), pid);
+                or_panic!(stream.write_all(b"hello"));
+            }
+        });
+
+        for _ in 0..2 {
+            let mut stream =
+                or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(b"hello", &buf);
+        }
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn incoming_ok() {
+        let socket_path = Path::new("//./pipe/incomingoksock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut errors = 0;
+            for mut stream in listener.incoming_ok().on_error(|_| errors += 1).take(2) {
+                or_panic!(stream.write_all(b"hello"));
+            }
+            assert_eq!(0, errors);
+        });
+
+        for _ in 0..2 {
+            let mut stream =
+                or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(b"hello", &buf);
+        }
+
+        thread.join().unwrap();
+
+        let fatal = io::Error::from_raw_os_error(ERROR_INVALID_HANDLE as i32);
+        assert!(is_fatal_accept_error(&fatal));
+        let transient = io::Error::from_raw_os_error(ERROR_NO_DATA as i32);
+        assert!(!is_fatal_accept_error(&transient));
+    }
+
+    #[test]
+    fn incoming_ok_busy() {
+        let socket_path = Path::new("//./pipe/incomingokbusysock");
+
+        let (tx, rx) = mpsc::channel();
+        let mut listener = or_panic!(PipeListenerBuilder::new()
+            .max_instances(1)
+            .bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut errors = 0;
+            {
+                let mut incoming = listener.incoming_ok().on_error(|_| errors += 1);
+                tx.send(incoming.next().unwrap()).unwrap();
+                // fails with ERROR_PIPE_BUSY until the first stream is closed.
+                let mut stream = incoming.next().unwrap();
+                or_panic!(stream.write_all(b"hello"));
+            }
+            // backing off keeps the errors down to a handful.
+            assert!(errors > 0 && errors < 20, "{} errors", errors);
+        });
+
+        let _first_client =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let first = rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(300));
+        drop(first);
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn bind_with_same_options() {
+        let first_path = Path::new("//./pipe/sameoptionsfirstsock");
+        let second_path = Path::new("//./pipe/sameoptionssecondsock");
+
+        let first = or_panic!(PipeListenerBuilder::new()
+            .mode(PipeMode::Message)
+            .out_buffer_size(2048)
+            .bind(first_path));
+        let mut second = or_panic!(first.bind_with_same_options(second_path));
+        let thread = thread::spawn(move || {
+            let stream = or_panic!(second.accept());
+            let info = or_panic!(stream.info());
+            assert_eq!(PipeMode::Message, info.mode());
+            assert_eq!(2048, info.out_buffer_size);
+        });
+
+        drop(or_panic!(PipeStream::connect_timeout(second_path, Duration::from_secs(5))));
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown() {
+        let socket_path = Path::new("//./pipe/shutdownsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            or_panic!(stream.shutdown(Shutdown::Write));
+            let err = stream.write(b"x").unwrap_err();
+            assert_eq!(io::ErrorKind::BrokenPipe, err.kind());
+            // reading still works after shutting down writes.
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(b"world", &buf);
+            or_panic!(stream.shutdown(Shutdown::Both));
+            assert_eq!(0, or_panic!(stream.read(&mut buf)));
         });
 
-        let mut stream = or_panic!(PipeStream::connect(socket_path));
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        or_panic!(stream.write_all(b"hello"));
+        or_panic!(stream.write_all(b"world"));
+        // shutting down both ends on the server disconnects the client.
+        let mut buf = vec![];
+        or_panic!(stream.read_to_end(&mut buf));
+        assert!(buf.is_empty());
 
-        or_panic!(stream.write_all(msg1));
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn read_large_message() {
+        let socket_path = Path::new("//./pipe/readlargemessagesock");
+        let message: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let expected = message.clone();
+
+        let mut listener = or_panic!(PipeListener::bind_with_mode(socket_path, PipeMode::Message));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(&message));
+            assert_eq!(0, or_panic!(stream.read(&mut [0; 4])));
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        or_panic!(stream.set_read_mode(PipeMode::Message));
+        let mut received = vec![];
+        while received.len() < expected.len() {
+            let mut buf = [0; 64];
+            let n = or_panic!(stream.read(&mut buf));
+            assert!(n > 0);
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(expected, received);
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn debug() {
+        let socket_path = Path::new("//./pipe/debugsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let stream = or_panic!(listener.accept());
+            let debug = format!("{:?}", stream);
+            assert!(debug.contains("server: true"));
+            assert!(debug.contains(&format!("{:?}", stream.as_raw_handle())));
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        assert!(format!("{:?}", stream).contains("server: false"));
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn path() {
+        let socket_path = Path::new("//./pipe/pathsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let stream = or_panic!(listener.accept());
+            assert_eq!(Some(socket_path.as_os_str()), stream.path());
+            assert!(format!("{:?}", stream).contains("pathsock"));
+            let handle = stream.into_raw_handle();
+            let stream = unsafe { PipeStream::from_raw_handle_server(handle) };
+            assert_eq!(None, stream.path());
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        assert_eq!(Some(socket_path.as_os_str()), stream.path());
+        assert_eq!(Some(socket_path.as_os_str()), or_panic!(stream.try_clone()).path());
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn configure_client_state() {
+        let socket_path = Path::new("//./pipe/configureclientstatesock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let stream = or_panic!(listener.accept());
+            let timeout = Duration::from_millis(10);
+            let err = stream.configure_client_state(PipeMode::Byte, 4096, timeout).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let err = stream.configure_client_state(PipeMode::Byte, 0, Duration::from_millis(10))
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn serve() {
+        let socket_path = Path::new("//./pipe/servesock");
+
+        let shutdown = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                super::serve(socket_path,
+                             |mut stream| or_panic!(stream.write_all(b"hello")),
+                             &shutdown)
+            });
+
+            for _ in 0..2 {
+                let mut stream =
+                    or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+                let mut buf = [0; 5];
+                or_panic!(stream.read_exact(&mut buf));
+                assert_eq!(b"hello", &buf);
+            }
+
+            shutdown.store(true, Ordering::SeqCst);
+            or_panic!(server.join().unwrap());
+        });
+
+        // the listener is gone once serve returns.
+        let err = PipeStream::connect(socket_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn serve_busy() {
+        let socket_path = Path::new("//./pipe/servebusysock");
+
+        let mut listener = or_panic!(PipeListenerBuilder::new()
+            .overlapped(true)
+            .max_instances(1)
+            .bind(socket_path));
+        let shutdown = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                // holding on to the stream leaves accept failing with
+                // ERROR_PIPE_BUSY.
+                let mut streams = vec![];
+                let result = listener.serve(&shutdown, |stream| streams.push(stream));
+                (result, streams.len())
+            });
+
+            let _client =
+                or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+            thread::sleep(Duration::from_millis(300));
+            let start = Instant::now();
+            shutdown.store(true, Ordering::SeqCst);
+            let (result, accepted) = server.join().unwrap();
+            or_panic!(result);
+            assert_eq!(1, accepted);
+            assert!(start.elapsed() < Duration::from_secs(1));
+        });
+    }
+
+    #[test]
+    fn connect_or_create() {
+        let socket_path = Path::new("//./pipe/connectorcreatesock");
+
+        // whichever peer gets there first becomes the server.
+        let peer = move || {
+            let (mut stream, role) = or_panic!(super::connect_or_create(socket_path));
+            if role == Role::Client {
+                or_panic!(stream.write_all(b"hello"));
+            } else {
+                let mut buf = [0; 5];
+                or_panic!(stream.read_exact(&mut buf));
+                assert_eq!(&buf, b"hello");
+            }
+            role
+        };
+        let thread = thread::spawn(peer);
+        let role = peer();
+        assert!(role != thread.join().unwrap());
+    }
+
+    #[test]
+    fn connect_retry() {
+        let socket_path = Path::new("//./pipe/connectretrysock");
+
+        let delay = Duration::from_millis(10);
+        let err = PipeStream::connect_retry(socket_path, 2, delay).unwrap_err();
+        assert_eq!(Some(ERROR_FILE_NOT_FOUND as i32), err.raw_os_error());
+
+        let thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let mut listener = or_panic!(PipeListener::bind(socket_path));
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let mut stream = or_panic!(PipeStream::connect_retry(socket_path, 500, delay));
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn connect_backoff() {
+        let socket_path = Path::new("//./pipe/connectbackoffsock");
+
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+        };
+        assert_eq!(Duration::from_millis(10), backoff.delay(1));
+        assert_eq!(Duration::from_millis(40), backoff.delay(3));
+        assert_eq!(Duration::from_millis(50), backoff.delay(4));
+        assert_eq!(Duration::from_millis(50), backoff.delay(100));
+
+        let mut delays = vec![];
+        let err = PipeStreamBuilder::new()
+            .connect_retry_with(socket_path, 3, |failed| {
+                delays.push(failed);
+                Duration::from_millis(1)
+            })
+            .unwrap_err();
+        assert_eq!(Some(ERROR_FILE_NOT_FOUND as i32), err.raw_os_error());
+        assert_eq!(vec![1, 2], delays);
+
+        let thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let mut listener = or_panic!(PipeListener::bind(socket_path));
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let mut stream =
+            or_panic!(PipeStreamBuilder::new().connect_backoff(socket_path, 100, backoff));
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn try_from_owned_handle() {
+        let socket_path = Path::new("//./pipe/tryfromownedhandlesock");
+
+        let file = OwnedHandle::from(or_panic!(std::fs::File::open("Cargo.toml")));
+        let raw = file.as_raw_handle();
+        let err = PipeStream::try_from(file).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.1.kind());
+        // the handle comes back still open.
+        assert_eq!(raw, err.0.as_raw_handle());
+        or_panic!(std::fs::File::from(err.0).metadata());
+
+        let mut listener = or_panic!(PipeListener::bind_overlapped(socket_path));
+        let thread = thread::spawn(move || {
+            let handle = OwnedHandle::from(or_panic!(listener.accept()));
+            let mut stream = or_panic!(PipeStream::try_from(handle).map_err(|err| err.1));
+            assert!(stream.is_server());
+            // the handle was opened for overlapped I/O, so the stream must be.
+            assert!(stream.is_overlapped());
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let client = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut stream = or_panic!(PipeStream::try_from(OwnedHandle::from(client))
+            .map_err(|err| err.1));
+        assert!(!stream.is_server());
+        assert!(!stream.is_overlapped());
         let mut buf = vec![];
         or_panic!(stream.read_to_end(&mut buf));
-        assert_eq!(&msg2[..], &buf[..]);
+        assert_eq!(&b"hello"[..], &buf[..]);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn owned_handle() {
+        let socket_path = Path::new("//./pipe/ownedhandlesock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let raw = stream.as_raw_handle();
+        assert_eq!(raw, stream.as_handle().as_raw_handle());
+        let handle = OwnedHandle::from(stream);
+        assert_eq!(raw, handle.as_raw_handle());
+        let mut file = std::fs::File::from(handle);
+        let mut buf = [0; 5];
+        or_panic!(file.read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn drop_disconnects_then_closes() {
+        let socket_path = Path::new("//./pipe/dropordersock");
+
+        let mut listener = or_panic!(PipeListener::bind_with_max_instances(socket_path, 1));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+            drop(stream);
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"world"));
+        });
+
+        let mut first =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut buf = vec![];
+        or_panic!(first.read_to_end(&mut buf));
+        assert_eq!(&b"hello"[..], &buf[..]);
+        // the pipe was disconnected, as just closing it would give ERROR_NO_DATA.
+        let err = first.write(b"x").unwrap_err();
+        assert_eq!(Some(ERROR_PIPE_NOT_CONNECTED as i32), err.raw_os_error());
+
+        // the only instance was closed, so the next client gets a new one.
+        let mut second =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut buf = [0; 5];
+        or_panic!(second.read_exact(&mut buf));
+        assert_eq!(b"world", &buf);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn connect_busy() {
+        let socket_path = Path::new("//./pipe/connectbusysock");
+
+        let err = PipeStream::connect(socket_path).unwrap_err();
+        assert_eq!(Some(ERROR_FILE_NOT_FOUND as i32), err.raw_os_error());
+
+        let mut listener = or_panic!(PipeListener::bind_with_max_instances(socket_path, 1));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            assert_eq!(0, or_panic!(stream.read(&mut [0; 4])));
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let err = PipeStream::connect(socket_path).unwrap_err();
+        assert_eq!(Some(ERROR_PIPE_BUSY as i32), err.raw_os_error());
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn impersonate_client() {
+        let socket_path = Path::new("//./pipe/impersonateclientsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            let guard = or_panic!(stream.impersonate_client());
+            drop(guard);
+            or_panic!(stream.write_all(b"done!"));
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let err = stream.impersonate_client().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        or_panic!(stream.write_all(b"hello"));
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"done!", &buf);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn pipe_forward() {
+        let src_path = Path::new("//./pipe/forwardsrcsock");
+        let dst_path = Path::new("//./pipe/forwarddstsock");
+        let data: Vec<u8> = (0..200000).map(|i| i as u8).collect();
+        let expected = data.clone();
+
+        let mut src_listener = or_panic!(PipeListener::bind(src_path));
+        let writer = thread::spawn(move || {
+            let mut stream = or_panic!(src_listener.accept());
+            or_panic!(stream.write_all(&data));
+        });
+        let mut dst_listener = or_panic!(PipeListener::bind(dst_path));
+        let reader = thread::spawn(move || {
+            let mut stream = or_panic!(dst_listener.accept());
+            let mut buf = vec![];
+            or_panic!(stream.read_to_end(&mut buf));
+            assert_eq!(expected, buf);
+        });
+
+        let mut src = or_panic!(PipeStream::connect_timeout(src_path, Duration::from_secs(5)));
+        let mut dst = or_panic!(PipeStream::connect_timeout(dst_path, Duration::from_secs(5)));
+        assert_eq!(200000, or_panic!(super::pipe_forward(&mut src, &mut dst)));
+        drop(dst);
+
+        writer.join().unwrap();
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn backlog() {
+        let socket_path = Path::new("//./pipe/backlogsock");
+
+        assert!(PipeListener::bind_with_backlog(socket_path, 0).is_err());
+
+        let mut listener = or_panic!(PipeListener::bind_with_backlog(socket_path, 4));
+        // every client finds an instance waiting without the server accepting.
+        let streams: Vec<_> = (0..4)
+            .map(|_| or_panic!(PipeStream::connect(socket_path)))
+            .collect();
+
+        let thread = thread::spawn(move || for stream in listener.incoming().take(4) {
+            let mut stream = or_panic!(stream);
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        for mut stream in streams {
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            assert_eq!(b"hello", &buf);
+        }
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn wait_readable() {
+        let first_path = Path::new("//./pipe/waitreadablefirstsock");
+        let second_path = Path::new("//./pipe/waitreadablesecondsock");
+
+        let mut first_listener = or_panic!(PipeListener::bind(first_path));
+        let first = thread::spawn(move || {
+            let mut stream = or_panic!(first_listener.accept());
+            assert_eq!(0, or_panic!(stream.read(&mut [0; 4])));
+        });
+        let mut second_listener = or_panic!(PipeListener::bind(second_path));
+        let second = thread::spawn(move || {
+            let mut stream = or_panic!(second_listener.accept());
+            thread::sleep(Duration::from_millis(50));
+            or_panic!(stream.write_all(b"hello"));
+            assert_eq!(0, or_panic!(stream.read(&mut [0; 4])));
+        });
+
+        let mut streams = vec![];
+        for path in &[first_path, second_path] {
+            let stream = or_panic!(PipeStreamBuilder::new()
+                .overlapped(true)
+                .connect_timeout(path, Duration::from_secs(5)));
+            streams.push(stream);
+        }
+
+        {
+            let refs: Vec<_> = streams.iter().collect();
+            let timeout = Some(Duration::from_millis(10));
+            let err = super::wait_readable(&refs[..1], timeout).unwrap_err();
+            assert_eq!(io::ErrorKind::TimedOut, err.kind());
+            assert_eq!(1, or_panic!(super::wait_readable(&refs, Some(Duration::from_secs(5)))));
+        }
+        // waiting didn't consume anything.
+        let mut buf = [0; 5];
+        or_panic!(streams[1].read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+        drop(streams);
+
+        first.join().unwrap();
+        second.join().unwrap();
+    }
+
+    #[test]
+    fn large_write() {
+        let socket_path = Path::new("//./pipe/largewritesock");
+        let data: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+
+        let mut listener =
+            or_panic!(PipeListener::bind_with_buffer_sizes(socket_path, 4096, 4096));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = vec![];
+            or_panic!(stream.read_to_end(&mut buf));
+            assert_eq!(expected, buf);
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        let mut written = 0;
+        while written < data.len() {
+            let n = or_panic!(stream.write(&data[written..]));
+            assert!(n > 0);
+            written += n;
+        }
+        assert_eq!(data.len(), written);
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn write_all_vectored() {
+        let socket_path = Path::new("//./pipe/writeallvectoredsock");
+        let data: Vec<u8> = (0..100000).map(|i| i as u8).collect();
+        let expected = data.clone();
+
+        let mut listener =
+            or_panic!(PipeListener::bind_with_buffer_sizes(socket_path, 4096, 4096));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = vec![];
+            or_panic!(stream.read_to_end(&mut buf));
+            assert_eq!(expected, buf);
+        });
+
+        let stream = or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        or_panic!(stream.write_all_vectored(&mut []));
+        let mut bufs = [IoSlice::new(b""),
+                        IoSlice::new(&data[..10]),
+                        IoSlice::new(b""),
+                        IoSlice::new(&data[10..50000]),
+                        IoSlice::new(&data[50000..])];
+        or_panic!(stream.write_all_vectored(&mut bufs));
         drop(stream);
 
         thread.join().unwrap();
     }
 
+    #[test]
+    fn pipe_name() {
+        assert_eq!(r"\\.\pipe\pipenamesock", or_panic!(PipeName::local("pipenamesock")).as_str());
+        assert_eq!(or_panic!(PipeName::local("pipenamesock")),
+                   or_panic!(PipeName::global(r"Global\pipenamesock")));
+        assert_eq!(r"\\server\pipe\name", or_panic!(PipeName::remote("server", "name")).as_str());
+        assert!(PipeName::local("").is_err());
+        assert!(PipeName::local(r"a\b").is_err());
+        assert!(PipeName::local("a/b").is_err());
+        assert!(PipeName::local("a\0b").is_err());
+        assert!(PipeName::local(&"x".repeat(247)).is_ok());
+        assert!(PipeName::local(&"x".repeat(248)).is_err());
+
+        let name = or_panic!(PipeName::from_path(Path::new("//./pipe/LOCAL/pipenamesock")));
+        assert_eq!(r"\\.\pipe\LOCAL\pipenamesock", name.as_str());
+        assert!(PipeName::from_path(Path::new(r"\\server\PIPE\name")).is_ok());
+        assert!(PipeName::from_path(Path::new(r"\\.\pipe\")).is_err());
+        assert!(PipeName::from_path(Path::new(r"\\.\mailslot\name")).is_err());
+        assert!(PipeName::from_path(Path::new(r"C:\pipe\name")).is_err());
+        let err = PipeListener::bind(r"C:\pipe\name").unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let err = PipeStream::connect("pipenamesock").unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        let name = or_panic!(PipeName::local("pipenamesock"));
+        let mut listener = or_panic!(PipeListener::bind(&name));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            or_panic!(stream.write_all(b"hello"));
+        });
+
+        let mut stream = or_panic!(PipeStream::connect_timeout(&name, Duration::from_secs(5)));
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn current_instances() {
+        let socket_path = Path::new("//./pipe/currentinstancessock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut streams = vec![];
+            let mut last = 0;
+            for _ in 0..3 {
+                let stream = or_panic!(listener.accept());
+                let instances = or_panic!(stream.current_instances());
+                assert!(instances > last);
+                last = instances;
+                streams.push(stream);
+            }
+        });
+
+        let clients: Vec<_> = (0..3)
+            .map(|_| or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5))))
+            .collect();
+
+        thread.join().unwrap();
+        drop(clients);
+    }
+
+    #[test]
+    fn handle_state() {
+        let socket_path = Path::new("//./pipe/handlestatesock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            let mut buf = [0; 5];
+            or_panic!(stream.read_exact(&mut buf));
+            let state = or_panic!(stream.handle_state());
+            assert!(!state.nonblocking);
+            assert_eq!(state.read_mode, PipeMode::Byte);
+            assert!(state.current_instances >= 1);
+            assert_eq!(state.max_collection_count, None);
+            assert_eq!(state.collect_data_timeout, None);
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        or_panic!(stream.write_all(b"hello"));
+        let state = or_panic!(stream.handle_state());
+        assert_eq!(state.read_mode, PipeMode::Byte);
+        assert_eq!(state.user_name, None);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn lazy_flush() {
+        let socket_path = Path::new("//./pipe/lazyflushsock");
+
+        let mut listener = or_panic!(PipeListener::bind(socket_path));
+        let thread = thread::spawn(move || {
+            let mut stream = or_panic!(listener.accept());
+            stream.set_lazy_flush(true);
+            or_panic!(stream.write_all(b"hello"));
+            // returns even though the client hasn't read anything.
+            or_panic!(stream.flush());
+            or_panic!(stream.sync_data());
+        });
+
+        let mut stream =
+            or_panic!(PipeStream::connect_timeout(socket_path, Duration::from_secs(5)));
+        thread::sleep(Duration::from_millis(50));
+        let mut buf = [0; 5];
+        or_panic!(stream.read_exact(&mut buf));
+        assert_eq!(b"hello", &buf);
+
+        thread.join().unwrap();
+    }
+
     #[test]
     fn iter() {
         let socket_path = Path::new("//./pipe/itersock");